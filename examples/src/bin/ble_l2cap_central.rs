@@ -164,7 +164,10 @@ async fn main(spawner: Spawner) {
     info!("connected");
 
     let l = l2cap::L2cap::<Packet>::init(sd);
-    let config = l2cap::Config { credits: 8 };
+    let config = l2cap::Config {
+        credits: 8,
+        ..Default::default()
+    };
     let ch = unwrap!(l.setup(&conn, &config, PSM).await);
     info!("l2cap connected");
 
@@ -132,7 +132,10 @@ async fn main(spawner: Spawner) {
 
         info!("advertising done!");
 
-        let config = l2cap::Config { credits: 8 };
+        let config = l2cap::Config {
+            credits: 8,
+            ..Default::default()
+        };
         let ch = unwrap!(l.listen(&conn, &config, PSM).await);
         info!("l2cap connected");
 
@@ -0,0 +1,67 @@
+//! Parsing of advertising and scan response data.
+
+/// AD types defined in the Bluetooth Assigned Numbers.
+pub mod ad_type {
+    pub const FLAGS: u8 = 0x01;
+    pub const INCOMPLETE_16BIT_SERVICE_UUIDS: u8 = 0x02;
+    pub const COMPLETE_16BIT_SERVICE_UUIDS: u8 = 0x03;
+    pub const INCOMPLETE_128BIT_SERVICE_UUIDS: u8 = 0x06;
+    pub const COMPLETE_128BIT_SERVICE_UUIDS: u8 = 0x07;
+    pub const SHORTENED_LOCAL_NAME: u8 = 0x08;
+    pub const COMPLETE_LOCAL_NAME: u8 = 0x09;
+    pub const TX_POWER_LEVEL: u8 = 0x0A;
+    pub const PERIPHERAL_CONN_INTERVAL_RANGE: u8 = 0x12;
+    pub const SERVICE_DATA_16BIT: u8 = 0x16;
+    pub const APPEARANCE: u8 = 0x19;
+    pub const MANUFACTURER_SPECIFIC_DATA: u8 = 0xFF;
+}
+
+/// Iterator over the AD structures in advertising data, yielding `(ad_type, data)` pairs.
+///
+/// Iteration stops at the first malformed structure: one with a zero length or running past
+/// the end of the data.
+#[derive(Clone)]
+pub struct AdvertisementDataIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> AdvertisementDataIter<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Data of the first AD structure of type `ad_type`.
+    pub fn find(mut self, ad_type: u8) -> Option<&'a [u8]> {
+        // Not `self.find`, which would resolve to this inherent method.
+        Iterator::find(&mut self, |(t, _)| *t == ad_type).map(|(_, data)| data)
+    }
+
+    /// The complete local name, or `None` if absent or not valid UTF-8.
+    pub fn complete_local_name(self) -> Option<&'a str> {
+        self.find(ad_type::COMPLETE_LOCAL_NAME)
+            .and_then(|name| core::str::from_utf8(name).ok())
+    }
+
+    /// The shortened local name, or `None` if absent or not valid UTF-8.
+    pub fn shortened_local_name(self) -> Option<&'a str> {
+        self.find(ad_type::SHORTENED_LOCAL_NAME)
+            .and_then(|name| core::str::from_utf8(name).ok())
+    }
+}
+
+impl<'a> Iterator for AdvertisementDataIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (len, rest) = match self.data {
+            [len, rest @ ..] if *len != 0 && *len as usize <= rest.len() => (*len as usize, rest),
+            _ => {
+                self.data = &[];
+                return None;
+            }
+        };
+        let (ad, next) = rest.split_at(len);
+        self.data = next;
+        Some((ad[0], &ad[1..]))
+    }
+}
@@ -3,10 +3,15 @@
 //! Typically the Central device is the higher-powered device, such as a smartphone or laptop, since scanning is more
 //! power-hungry than advertising.
 
-use core::{mem, ptr};
+use core::{mem, ptr, slice};
 
+use futures::{stream, Stream};
+
+use crate::ble::advertisement::{ad_type, AdvertisementDataIter};
+#[cfg(feature = "ble-gatt-client")]
+use crate::ble::gatt_client::MtuExchangeError;
 use crate::ble::types::*;
-use crate::ble::{Address, Connection};
+use crate::ble::{get_address, Address, ConnParams, Connection};
 use crate::util::{get_union_field, OnDrop, Portal};
 use crate::{raw, RawError, Softdevice};
 
@@ -17,6 +22,15 @@ pub enum ConnectError {
     Timeout,
     NoAddresses,
     NoFreeConn,
+    /// The connection attempt was cancelled with [`connect_cancel`].
+    Cancelled,
+    /// [`connect_selected`] was called with an unlimited scan timeout, so its scan window
+    /// would never end.
+    NoScanTimeout,
+    /// The connection was established, but the ATT MTU exchange failed, so it's
+    /// disconnected again.
+    #[cfg(feature = "ble-gatt-client")]
+    MtuExchange(MtuExchangeError),
     Raw(RawError),
 }
 
@@ -26,6 +40,28 @@ impl From<RawError> for ConnectError {
     }
 }
 
+#[cfg(feature = "ble-gatt-client")]
+impl From<MtuExchangeError> for ConnectError {
+    fn from(err: MtuExchangeError) -> Self {
+        ConnectError::MtuExchange(err)
+    }
+}
+
+impl core::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConnectError::Timeout => f.write_str("connection timed out"),
+            ConnectError::NoAddresses => f.write_str("no addresses to connect to"),
+            ConnectError::NoFreeConn => f.write_str("no free connection slot"),
+            ConnectError::Cancelled => f.write_str("connection cancelled"),
+            ConnectError::NoScanTimeout => f.write_str("scan window needs a timeout"),
+            #[cfg(feature = "ble-gatt-client")]
+            ConnectError::MtuExchange(err) => write!(f, "ATT MTU exchange failed: {:?}", err),
+            ConnectError::Raw(err) => write!(f, "SoftDevice error: {:?}", err),
+        }
+    }
+}
+
 pub(crate) static CONNECT_PORTAL: Portal<*const raw::ble_evt_t> = Portal::new();
 
 // Begins an ATT MTU exchange procedure, followed by a data length update request as necessary.
@@ -38,6 +74,37 @@ pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Con
         return Err(ConnectError::NoAddresses);
     }
 
+    let mut conn_params = config.conn_params;
+    if config.use_peer_preferred_params {
+        let range = scan(_sd, &config.scan_config, |report| {
+            // Safety: `report` is the report passed to the scan callback.
+            Some(unsafe { AdvReport::from_raw(report) }.conn_interval_range())
+        })
+        .await
+        .map_err(|err| match err {
+            ScanError::Timeout => ConnectError::Timeout,
+            ScanError::Raw(err) => ConnectError::Raw(err),
+        })?;
+
+        if let Some((min, max)) = range {
+            debug!("peer preferred conn interval min={:?} max={:?}", min, max);
+            // 0xFFFF means no preference for that bound.
+            let pick = |peer: u16, ours: u16| if peer == 0xFFFF { ours } else { peer };
+            let min = pick(min, conn_params.min_conn_interval);
+            let max = pick(max, conn_params.max_conn_interval);
+            // The advertisement is untrusted, so check the resulting parameters like ours.
+            match ConnParams::from_micros(
+                min as u32 * 1250,
+                max as u32 * 1250,
+                conn_params.slave_latency,
+                conn_params.conn_sup_timeout as u32 * 10,
+            ) {
+                Ok(params) => conn_params = params.into(),
+                Err(_err) => warn!("ignoring peer preferred conn interval: {:?}", _err),
+            }
+        }
+    }
+
     let scan_params = config.scan_config.to_raw()?;
 
     let d = OnDrop::new(|| {
@@ -47,7 +114,7 @@ pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Con
         }
     });
 
-    let ret = unsafe { raw::sd_ble_gap_connect(ptr::null(), &scan_params, &config.conn_params, 1) };
+    let ret = unsafe { raw::sd_ble_gap_connect(ptr::null(), &scan_params, &conn_params, 1) };
     if let Err(err) = RawError::convert(ret) {
         warn!("sd_ble_gap_connect err {:?}", err);
         return Err(err.into());
@@ -55,8 +122,12 @@ pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Con
 
     debug!("connect started");
 
-    let conn = CONNECT_PORTAL
+    let res = CONNECT_PORTAL
         .wait_once(|ble_evt| unsafe {
+            // Null is sent by `connect_cancel`.
+            if ble_evt.is_null() {
+                return Err(ConnectError::Cancelled);
+            }
             match (*ble_evt).header.evt_id as u32 {
                 raw::BLE_GAP_EVTS_BLE_GAP_EVT_CONNECTED => {
                     let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
@@ -70,7 +141,18 @@ pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Con
                     match Connection::new(conn_handle, role, peer_address, conn_params) {
                         Ok(conn) => {
                             #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
-                            crate::ble::gap::do_data_length_update(conn_handle, ptr::null());
+                            let _ = crate::ble::gap::do_data_length_update(conn_handle, ptr::null());
+
+                            if let Some(phys) = config.phys {
+                                let p_gap_phys = raw::ble_gap_phys_t {
+                                    tx_phys: phys as u8,
+                                    rx_phys: phys as u8,
+                                };
+                                let ret = raw::sd_ble_gap_phy_update(conn_handle, &p_gap_phys);
+                                if let Err(_err) = RawError::convert(ret) {
+                                    warn!("sd_ble_gap_phy_update err {:?}", _err);
+                                }
+                            }
 
                             Ok(conn)
                         }
@@ -87,19 +169,155 @@ pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Con
                 e => panic!("unexpected event {}", e),
             }
         })
-        .await?;
+        .await;
 
+    let conn = match res {
+        Ok(conn) => conn,
+        Err(ConnectError::Cancelled) => {
+            // Already cancelled, nothing left to do.
+            d.defuse();
+            return Err(ConnectError::Cancelled);
+        }
+        Err(err) => return Err(err),
+    };
     d.defuse();
 
     #[cfg(feature = "ble-gatt-client")]
     {
         let mtu = config.att_mtu.unwrap_or(_sd.att_mtu);
-        unwrap!(crate::ble::gatt_client::att_mtu_exchange(&conn, mtu).await);
+        crate::ble::gatt_client::att_mtu_exchange(&conn, mtu).await?;
     }
 
     Ok(conn)
 }
 
+/// Cancel the connection attempt in progress.
+///
+/// The pending [`connect`] future resolves with [`ConnectError::Cancelled`]. Fails with
+/// [`RawError::InvalidState`] if no connection is being initiated, which includes the scanning
+/// phase of [`connect_selected`], [`connect_to_service`] and
+/// [`ConnectConfig::use_peer_preferred_params`].
+pub fn connect_cancel(_sd: &Softdevice) -> Result<(), RawError> {
+    let ret = unsafe { raw::sd_ble_gap_connect_cancel() };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_ble_gap_connect_cancel err {:?}", err);
+        return Err(err);
+    }
+
+    // The SoftDevice sends no event for a cancelled connection, wake up `connect` ourselves.
+    CONNECT_PORTAL.call(ptr::null());
+    Ok(())
+}
+
+/// Scan for an advertiser listing the service `uuid` in its advertising data, then connect to it.
+///
+/// The first matching advertiser is connected to, using `config` with the whitelist replaced by
+/// its address. `config.scan_config.whitelist` restricts the advertisers considered, and may be
+/// `None`. See [`AdvReport::advertises_service`] for how the UUID is matched.
+pub async fn connect_to_service(
+    sd: &Softdevice,
+    config: &ConnectConfig<'_>,
+    uuid: Uuid,
+) -> Result<Connection, ConnectError> {
+    let address = scan(sd, &config.scan_config, |report| {
+        // Safety: `report` is the report passed to the scan callback.
+        let report = unsafe { AdvReport::from_raw(report) };
+        report.advertises_service(&uuid).then(|| report.peer_address())
+    })
+    .await
+    .map_err(|err| match err {
+        ScanError::Timeout => ConnectError::Timeout,
+        ScanError::Raw(err) => ConnectError::Raw(err),
+    })?;
+    debug!("connecting to {:?}, advertising the service", address);
+    connect_to_address(sd, config, address).await
+}
+
+/// Scan until `predicate` accepts a report, then connect to that report's advertiser.
+///
+/// Scanning stops at the first report `predicate` returns `true` for, and the connection is
+/// initiated using `config` with the whitelist replaced by the advertiser's address.
+/// `config.scan_config.whitelist` restricts the reports considered, and may be `None`.
+pub async fn scan_and_connect<F>(
+    sd: &Softdevice,
+    config: &ConnectConfig<'_>,
+    mut predicate: F,
+) -> Result<Connection, ConnectError>
+where
+    F: FnMut(&ScanReport) -> bool,
+{
+    let address = scan_reports(sd, &config.scan_config, |report| {
+        predicate(report).then_some(report.peer_address)
+    })
+    .await
+    .map_err(|err| match err {
+        ScanError::Timeout => ConnectError::Timeout,
+        ScanError::Raw(err) => ConnectError::Raw(err),
+    })?;
+    debug!("connecting to {:?}, accepted by the predicate", address);
+    connect_to_address(sd, config, address).await
+}
+
+/// Connect using `config` with the whitelist replaced by `address`.
+async fn connect_to_address(
+    sd: &Softdevice,
+    config: &ConnectConfig<'_>,
+    address: Address,
+) -> Result<Connection, ConnectError> {
+    let whitelist = [&address];
+    let config = ConnectConfig {
+        scan_config: ScanConfig {
+            whitelist: Some(&whitelist),
+            ..config.scan_config
+        },
+        ..*config
+    };
+    connect(sd, &config).await
+}
+
+/// Scan for one scan window, then connect to the advertiser picked by `select`.
+///
+/// `select` is called with every advertising report received during the window, which
+/// is `config.scan_config.timeout`. It returns `true` to make
+/// the report's advertiser the candidate, replacing any previous one, for example when
+/// the report has a higher RSSI than the current candidate. `config.scan_config.whitelist`
+/// restricts the reports considered, and may be `None`.
+///
+/// The connection is then initiated with the same config, with the whitelist replaced by the
+/// chosen address. Returns [`ConnectError::NoAddresses`] if no candidate was selected, and
+/// [`ConnectError::NoScanTimeout`] without scanning if the scan timeout is unlimited.
+pub async fn connect_selected<F>(
+    sd: &Softdevice,
+    config: &ConnectConfig<'_>,
+    mut select: F,
+) -> Result<Connection, ConnectError>
+where
+    F: FnMut(&AdvReport<'_>) -> bool,
+{
+    if config.scan_config.timeout == raw::BLE_GAP_SCAN_TIMEOUT_UNLIMITED as u16 {
+        return Err(ConnectError::NoScanTimeout);
+    }
+
+    let mut candidate = None;
+    let res = scan(sd, &config.scan_config, |report| {
+        // Safety: `report` is the report passed to the scan callback.
+        let report = unsafe { AdvReport::from_raw(report) };
+        if select(&report) {
+            candidate = Some(report.peer_address());
+        }
+        None::<()>
+    })
+    .await;
+    match res {
+        Ok(()) | Err(ScanError::Timeout) => {}
+        Err(ScanError::Raw(err)) => return Err(err.into()),
+    }
+
+    let address = candidate.ok_or(ConnectError::NoAddresses)?;
+    debug!("connecting to selected {:?}", address);
+    connect_to_address(sd, config, address).await
+}
+
 #[derive(Copy, Clone)]
 pub struct ConnectConfig<'a> {
     /// Requested ATT_MTU size for the next connection that is established.
@@ -107,7 +325,27 @@ pub struct ConnectConfig<'a> {
     pub att_mtu: Option<u16>,
 
     pub scan_config: ScanConfig<'a>,
+    /// Connection parameters in SoftDevice units, see [`ConnParams`](crate::ble::ConnParams)
+    /// to build them from milliseconds.
     pub conn_params: raw::ble_gap_conn_params_t,
+
+    /// Use the connection interval range the peer advertises, if any, instead of the one in
+    /// `conn_params`.
+    ///
+    /// The SoftDevice does not report the advertisement it connects on, so this scans for the
+    /// first advertisement from the whitelist before connecting. Only that advertisement is
+    /// checked for a Peripheral Connection Interval Range AD structure.
+    ///
+    /// The advertised range is ignored if it isn't valid, see [`ConnParams::from_micros`], or
+    /// if it doesn't fit the slave latency and supervision timeout in `conn_params`.
+    pub use_peer_preferred_params: bool,
+
+    /// PHYs to request right after the connection is established.
+    ///
+    /// The connection is initiated on the PHYs in `scan_config.phys`, so this allows e.g.
+    /// connecting on the Coded PHY for range, then switching to 2Mbps for throughput.
+    /// If None, the PHYs are left as they are.
+    pub phys: Option<PhySet>,
 }
 
 impl<'a> Default for ConnectConfig<'a> {
@@ -122,6 +360,8 @@ impl<'a> Default for ConnectConfig<'a> {
                 slave_latency: 0,
                 conn_sup_timeout: 400, // 4s
             },
+            use_peer_preferred_params: false,
+            phys: None,
         }
     }
 }
@@ -140,46 +380,35 @@ impl From<RawError> for ScanError {
     }
 }
 
+impl core::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ScanError::Timeout => f.write_str("scan timed out"),
+            ScanError::Raw(err) => write!(f, "SoftDevice error: {:?}", err),
+        }
+    }
+}
+
 pub(crate) static SCAN_PORTAL: Portal<*const raw::ble_evt_t> = Portal::new();
 
+/// Scan for advertisements, calling `f` with each advertising report received.
+///
+/// If `f` returns `Some`, scanning stops and the value is returned. If it returns `None`, scanning
+/// continues, so `f` can process any number of reports before deciding it's done. Use
+/// [`AdvReport::from_raw`] to access the report's contents.
+///
+/// Extended advertising data received over a chain of PDUs is reassembled by the SoftDevice
+/// and passed to `f` in a single report. See [`AdvDataStatus`] for data that didn't make it.
 pub async fn scan<'a, F, R>(_sd: &Softdevice, config: &ScanConfig<'a>, mut f: F) -> Result<R, ScanError>
 where
     F: for<'b> FnMut(&'b raw::ble_gap_evt_adv_report_t) -> Option<R>,
 {
     let scan_params = config.to_raw()?;
 
-    // Buffer to store received advertisement data.
-    const BUF_LEN: usize = 256;
-
-    // Both of these are intentionally static because Softdevice will,
-    // sometimes, write to the buffer after scan_stop() has been
-    // called, somewhere around evt_get().
-    //
-    // This can result in UB as a use-after-free, given the buffer
-    // has been dropped and the scanning has been stopped.
-    static mut BUF: [u8; BUF_LEN] = [0u8; BUF_LEN];
-    static mut BUF_DATA: raw::ble_data_t = raw::ble_data_t {
-        p_data: unsafe { BUF.as_mut_ptr() },
-        len: BUF_LEN as u16,
-    };
-
-    let ret = unsafe { raw::sd_ble_gap_scan_start(&scan_params, &BUF_DATA) };
-    match RawError::convert(ret) {
-        Ok(()) => {}
-        Err(err) => {
-            warn!("sd_ble_gap_scan_start err {:?}", err);
-            return Err(ScanError::Raw(err));
-        }
-    }
+    start_scan(&scan_params)?;
+    let _d = OnDrop::new(stop_scan);
 
-    let _d = OnDrop::new(|| {
-        let ret = unsafe { raw::sd_ble_gap_scan_stop() };
-        match RawError::convert(ret) {
-            Ok(_) => {}
-            Err(RawError::InvalidState) => {} // scan stopped itself due to timeout, erroring is normal.
-            Err(_e) => warn!("sd_ble_gap_scan_stop: {:?}", _e),
-        }
-    });
+    let mut seen = SeenAdvertisers::new();
 
     debug!("Scan started");
     let res = SCAN_PORTAL
@@ -189,23 +418,17 @@ where
                 raw::BLE_GAP_EVTS_BLE_GAP_EVT_ADV_REPORT => {
                     let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
                     let params = &gap_evt.params.adv_report;
-                    if let Some(r) = f(params) {
-                        return Some(Ok(r));
+                    let wanted = config.min_rssi.map_or(true, |min| params.rssi >= min)
+                        && (!config.dedup || seen.insert(&AdvReport::from_raw(params)));
+                    if wanted {
+                        if let Some(r) = f(params) {
+                            return Some(Ok(r));
+                        }
                     }
 
-                    // Resume scan
-                    let ret = raw::sd_ble_gap_scan_start(ptr::null(), &BUF_DATA);
-                    match RawError::convert(ret) {
-                        Ok(()) => {}
-
-                        // "The scanner has timed out when this function is called to continue scanning"
-                        Err(RawError::InvalidState) => return Some(Err(ScanError::Timeout)),
-
-                        Err(err) => {
-                            warn!("sd_ble_gap_scan_start resume err {:?}", err);
-                            return Some(Err(ScanError::Raw(err)));
-                        }
-                    };
+                    if let Err(err) = resume_scan() {
+                        return Some(Err(err));
+                    }
                     None
                 }
                 _ => None,
@@ -216,14 +439,638 @@ where
     Ok(res)
 }
 
+// Buffer to store received advertisement data.
+//
+// The SoftDevice needs at least BLE_GAP_SCAN_BUFFER_MIN (31) bytes for legacy scanning and
+// BLE_GAP_SCAN_BUFFER_EXTENDED_MIN (255) bytes for extended scanning, and doesn't support
+// more than BLE_GAP_SCAN_BUFFER_EXTENDED_MAX_SUPPORTED (255) bytes per report, so this fits
+// any report whatever the scan config. Longer extended advertising data is reported truncated.
+const SCAN_BUF_LEN: usize = 256;
+
+// Both of these are intentionally static because Softdevice will,
+// sometimes, write to the buffer after scan_stop() has been
+// called, somewhere around evt_get().
+//
+// This can result in UB as a use-after-free, given the buffer
+// has been dropped and the scanning has been stopped.
+static mut SCAN_BUF: [u8; SCAN_BUF_LEN] = [0u8; SCAN_BUF_LEN];
+static mut SCAN_BUF_DATA: raw::ble_data_t = raw::ble_data_t {
+    p_data: unsafe { SCAN_BUF.as_mut_ptr() },
+    len: SCAN_BUF_LEN as u16,
+};
+
+fn start_scan(scan_params: &raw::ble_gap_scan_params_t) -> Result<(), ScanError> {
+    let ret = unsafe { raw::sd_ble_gap_scan_start(scan_params, &SCAN_BUF_DATA) };
+    match RawError::convert(ret) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            warn!("sd_ble_gap_scan_start err {:?}", err);
+            Err(ScanError::Raw(err))
+        }
+    }
+}
+
+/// Continue scanning after an advertising report, handing the scan buffer back to the SoftDevice.
+///
+/// The SoftDevice pauses scanning after each complete report so the application can read the
+/// buffer, and this is the only way to resume it, so it has to be called once per report.
+/// Errors are returned as [`ScanError`] to end the scan, rather than silently stopping it.
+fn resume_scan() -> Result<(), ScanError> {
+    let ret = unsafe { raw::sd_ble_gap_scan_start(ptr::null(), &SCAN_BUF_DATA) };
+    match RawError::convert(ret) {
+        Ok(()) => Ok(()),
+
+        // "The scanner has timed out when this function is called to continue scanning"
+        Err(RawError::InvalidState) => Err(ScanError::Timeout),
+
+        Err(err) => {
+            warn!("sd_ble_gap_scan_start resume err {:?}", err);
+            Err(ScanError::Raw(err))
+        }
+    }
+}
+
+fn stop_scan() {
+    let ret = unsafe { raw::sd_ble_gap_scan_stop() };
+    match RawError::convert(ret) {
+        Ok(_) => {}
+        Err(RawError::InvalidState) => {} // scan stopped itself due to timeout, erroring is normal.
+        Err(_e) => warn!("sd_ble_gap_scan_stop: {:?}", _e),
+    }
+}
+
+/// An owned copy of an advertising report, as passed to [`scan_reports`] and yielded by
+/// [`scan_stream`].
+///
+/// Unlike [`AdvReport`], this can be kept after the scan callback returns.
+#[derive(Clone)]
+pub struct ScanReport {
+    /// Address of the advertiser.
+    pub peer_address: Address,
+    /// Received signal strength of the last packet, in dBm.
+    pub rssi: i8,
+    /// TX power advertised by the peer in the extended advertising header, in dBm.
+    /// `127` if not available.
+    pub tx_power: i8,
+    /// Kind of advertising PDU the report was received from.
+    pub adv_type: AdvReportType,
+    /// Advertising or scan response data.
+    pub data: heapless::Vec<u8, SCAN_BUF_LEN>,
+}
+
+impl ScanReport {
+    /// Copy a raw advertising report.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`AdvReport::from_raw`].
+    pub unsafe fn from_raw(raw: &raw::ble_gap_evt_adv_report_t) -> Self {
+        AdvReport::from_raw(raw).into()
+    }
+}
+
+impl<'a> From<AdvReport<'a>> for ScanReport {
+    fn from(report: AdvReport<'a>) -> Self {
+        let mut data = heapless::Vec::new();
+        // Can't fail, the SoftDevice receives reports into a buffer of the same size.
+        let _ = data.extend_from_slice(report.data());
+        Self {
+            peer_address: report.peer_address(),
+            rssi: report.rssi(),
+            tx_power: report.tx_power(),
+            adv_type: report.adv_type(),
+            data,
+        }
+    }
+}
+
+/// Like [`scan`], but `f` is passed an owned copy of each advertising report.
+pub async fn scan_reports<'a, F, R>(sd: &Softdevice, config: &ScanConfig<'a>, mut f: F) -> Result<R, ScanError>
+where
+    F: FnMut(&ScanReport) -> Option<R>,
+{
+    // Safety: `report` is the report passed to the scan callback.
+    scan(sd, config, |report| f(&unsafe { ScanReport::from_raw(report) })).await
+}
+
+/// Advertising data from an advertiser, merged with its scan response.
+///
+/// Produced by [`ScanResponseMerger`].
+#[derive(Clone)]
+pub struct MergedScanReport {
+    /// Address of the advertiser.
+    pub peer_address: Address,
+    /// Received signal strength of the last report, in dBm.
+    pub rssi: i8,
+    /// Kind of advertising PDU the advertising data was received from, or the scan response's
+    /// if the advertisement itself was missed.
+    pub adv_type: AdvReportType,
+    /// Advertising data. Empty if only the scan response was received.
+    pub adv_data: heapless::Vec<u8, SCAN_BUF_LEN>,
+    /// Scan response data, if one was received.
+    pub scan_response: Option<heapless::Vec<u8, SCAN_BUF_LEN>>,
+}
+
+impl MergedScanReport {
+    fn new(adv: ScanReport, scan_response: Option<ScanReport>) -> Self {
+        Self {
+            peer_address: adv.peer_address,
+            rssi: scan_response.as_ref().map_or(adv.rssi, |r| r.rssi),
+            adv_type: adv.adv_type,
+            adv_data: adv.data,
+            scan_response: scan_response.map(|r| r.data),
+        }
+    }
+
+    /// Iterate over the AD structures of the advertising data, followed by those of the
+    /// scan response.
+    pub fn ad_structures(&self) -> impl Iterator<Item = (u8, &[u8])> + '_ {
+        let scan_response = self.scan_response.as_deref().unwrap_or(&[]);
+        AdvertisementDataIter::new(&self.adv_data).chain(AdvertisementDataIter::new(scan_response))
+    }
+
+    /// The complete local name from either the advertising data or the scan response, or `None`
+    /// if absent or not valid UTF-8.
+    pub fn complete_local_name(&self) -> Option<&str> {
+        self.ad_structures()
+            .find(|(t, _)| *t == ad_type::COMPLETE_LOCAL_NAME)
+            .and_then(|(_, name)| core::str::from_utf8(name).ok())
+    }
+}
+
+/// Pairs advertisements with the scan response that follows them when active scanning.
+///
+/// The SoftDevice reports the scan response separately from the advertisement that solicited it.
+/// Feed every report to [`push`](Self::push): advertisements that can't have a scan response
+/// are returned right away, while scannable ones are held until their scan response arrives.
+///
+/// At most `N` advertisements are held. When full, the oldest one is returned without a scan
+/// response to make room. A newer advertisement from the same advertiser replaces the held one.
+pub struct ScanResponseMerger<const N: usize> {
+    pending: heapless::Vec<ScanReport, N>,
+}
+
+impl<const N: usize> ScanResponseMerger<N> {
+    pub const fn new() -> Self {
+        Self {
+            pending: heapless::Vec::new(),
+        }
+    }
+
+    /// Add a report, returning a merged report if one is ready.
+    pub fn push(&mut self, report: ScanReport) -> Option<MergedScanReport> {
+        if report.adv_type.scan_response {
+            return Some(match self.take(report.peer_address) {
+                Some(adv) => MergedScanReport::new(adv, Some(report)),
+                None => MergedScanReport {
+                    peer_address: report.peer_address,
+                    rssi: report.rssi,
+                    adv_type: report.adv_type,
+                    adv_data: heapless::Vec::new(),
+                    scan_response: Some(report.data),
+                },
+            });
+        }
+
+        if !report.adv_type.scannable {
+            return Some(MergedScanReport::new(report, None));
+        }
+
+        let evicted = match self.take(report.peer_address) {
+            Some(_) => None,
+            None if self.pending.is_full() => self.take_at(0),
+            None => None,
+        };
+        // Can't fail, there's room after the above.
+        let _ = self.pending.push(report);
+        evicted.map(|adv| MergedScanReport::new(adv, None))
+    }
+
+    /// Return the advertisements still waiting for a scan response, without one.
+    pub fn flush(&mut self) -> impl Iterator<Item = MergedScanReport> + '_ {
+        core::iter::from_fn(move || self.take_at(0).map(|adv| MergedScanReport::new(adv, None)))
+    }
+
+    fn take(&mut self, peer_address: Address) -> Option<ScanReport> {
+        let i = self.pending.iter().position(|r| r.peer_address == peer_address)?;
+        self.take_at(i)
+    }
+
+    fn take_at(&mut self, i: usize) -> Option<ScanReport> {
+        if i >= self.pending.len() {
+            return None;
+        }
+        // Keep the remaining advertisements in reception order.
+        self.pending[i..].rotate_left(1);
+        self.pending.pop()
+    }
+}
+
+impl<const N: usize> Default for ScanResponseMerger<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maximum number of advertisers remembered for [`ScanConfig::dedup`].
+pub const SCAN_DEDUP_MAX: usize = 32;
+
+// Advertisers already reported, for `ScanConfig::dedup`, oldest first.
+struct SeenAdvertisers {
+    seen: heapless::Vec<(Address, bool), SCAN_DEDUP_MAX>,
+}
+
+impl SeenAdvertisers {
+    const fn new() -> Self {
+        Self {
+            seen: heapless::Vec::new(),
+        }
+    }
+
+    /// Record the report's advertiser, returning true if it wasn't seen before.
+    fn insert(&mut self, report: &AdvReport<'_>) -> bool {
+        let key = (report.peer_address(), report.adv_type().scan_response);
+        if self.seen.contains(&key) {
+            return false;
+        }
+        if self.seen.is_full() {
+            self.seen.rotate_left(1);
+            self.seen.pop();
+        }
+        // Can't fail, there's room after the above.
+        let _ = self.seen.push(key);
+        true
+    }
+}
+
+enum ScanStreamState<'a> {
+    Start(ScanConfig<'a>),
+    Running(OnDrop<fn()>, SeenAdvertisers),
+    Done,
+}
+
+/// Scan for advertisements, yielding an owned copy of each advertising report received.
+///
+/// Scanning starts when the stream is first polled, and stops when the stream is dropped or
+/// ends. `config` is applied to the SoftDevice, e.g. its whitelist, only then. Like [`scan`], scanning is paused after each report until the stream is polled again.
+/// The stream ends after yielding an error, e.g. [`ScanError::Timeout`] once `config.timeout`
+/// expires.
+pub fn scan_stream<'a>(
+    _sd: &'a Softdevice,
+    config: &ScanConfig<'a>,
+) -> impl Stream<Item = Result<ScanReport, ScanError>> + 'a {
+    let state = ScanStreamState::Start(*config);
+    let min_rssi = config.min_rssi;
+    let dedup = config.dedup;
+    stream::unfold(state, move |state| async move {
+        let (guard, mut seen) = match state {
+            ScanStreamState::Start(config) => {
+                let scan_params = match config.to_raw() {
+                    Ok(scan_params) => scan_params,
+                    Err(err) => return Some((Err(err.into()), ScanStreamState::Done)),
+                };
+                if let Err(err) = start_scan(&scan_params) {
+                    return Some((Err(err), ScanStreamState::Done));
+                }
+                debug!("Scan started");
+                (OnDrop::new(stop_scan as fn()), SeenAdvertisers::new())
+            }
+            ScanStreamState::Running(guard, seen) => {
+                if let Err(err) = resume_scan() {
+                    return Some((Err(err), ScanStreamState::Done));
+                }
+                (guard, seen)
+            }
+            ScanStreamState::Done => return None,
+        };
+
+        let res = SCAN_PORTAL
+            .wait_many(|ble_evt| unsafe {
+                match (*ble_evt).header.evt_id as u32 {
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_TIMEOUT => Some(Err(ScanError::Timeout)),
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_ADV_REPORT => {
+                        let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                        let report = AdvReport::from_raw(&gap_evt.params.adv_report);
+                        if min_rssi.map_or(false, |min| report.rssi() < min) || (dedup && !seen.insert(&report)) {
+                            return resume_scan().err().map(Err);
+                        }
+                        Some(Ok(report.into()))
+                    }
+                    _ => None,
+                }
+            })
+            .await;
+
+        match res {
+            Ok(report) => Some((Ok(report), ScanStreamState::Running(guard, seen))),
+            Err(err) => Some((Err(err), ScanStreamState::Done)),
+        }
+    })
+}
+
+#[cfg(any(feature = "s132", feature = "s140"))]
+pub(crate) static SURVEY_PORTAL: Portal<*const raw::ble_evt_t> = Portal::new();
+
+/// Number of channels measured by [`channel_survey`]: the 37 data channels followed by
+/// the 3 advertising channels, indexed by channel index.
+#[cfg(any(feature = "s132", feature = "s140"))]
+pub const CHANNEL_SURVEY_CHANNELS: usize = raw::BLE_GAP_CHANNEL_COUNT as usize;
+
+#[cfg(any(feature = "s132", feature = "s140"))]
+enum SurveyStreamState {
+    Start(u32),
+    Running(OnDrop<fn()>),
+    Done,
+}
+
+#[cfg(any(feature = "s132", feature = "s140"))]
+fn stop_channel_survey() {
+    let ret = unsafe { raw::sd_ble_gap_qos_channel_survey_stop() };
+    if let Err(_e) = RawError::convert(ret) {
+        warn!("sd_ble_gap_qos_channel_survey_stop: {:?}", _e);
+    }
+}
+
+/// Run a QoS channel survey, yielding the measured energy on each channel, in dBm.
+///
+/// `interval_us` is the requested average interval between reports, or
+/// `BLE_GAP_QOS_CHANNEL_SURVEY_INTERVAL_CONTINUOUS` (0) to measure at every opportunity.
+/// The survey has the lowest priority of all radio activity, so reports may come less often.
+/// Channels without a measurement are set to `BLE_GAP_POWER_LEVEL_INVALID` (127).
+///
+/// The survey starts when the stream is first polled, and stops when the stream is dropped.
+/// Reports received while the stream is not being polled are dropped.
+///
+/// The channel survey role must be enabled in the SoftDevice config, by setting
+/// `qos_channel_survey_role_available` in `gap_role_count`. Otherwise starting the survey
+/// fails with [`RawError::Resources`].
+#[cfg(any(feature = "s132", feature = "s140"))]
+pub fn channel_survey(
+    _sd: &Softdevice,
+    interval_us: u32,
+) -> impl Stream<Item = Result<[i8; CHANNEL_SURVEY_CHANNELS], RawError>> + '_ {
+    stream::unfold(SurveyStreamState::Start(interval_us), |state| async move {
+        let guard = match state {
+            SurveyStreamState::Start(interval_us) => {
+                let ret = unsafe { raw::sd_ble_gap_qos_channel_survey_start(interval_us) };
+                if let Err(err) = RawError::convert(ret) {
+                    warn!("sd_ble_gap_qos_channel_survey_start err {:?}", err);
+                    return Some((Err(err), SurveyStreamState::Done));
+                }
+                debug!("Channel survey started");
+                OnDrop::new(stop_channel_survey as fn())
+            }
+            SurveyStreamState::Running(guard) => guard,
+            SurveyStreamState::Done => return None,
+        };
+
+        let energy = SURVEY_PORTAL
+            .wait_many(|ble_evt| unsafe {
+                match (*ble_evt).header.evt_id as u32 {
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_QOS_CHANNEL_SURVEY_REPORT => {
+                        let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                        Some(gap_evt.params.qos_channel_survey_report.channel_energy)
+                    }
+                    _ => None,
+                }
+            })
+            .await;
+
+        Some((Ok(energy), SurveyStreamState::Running(guard)))
+    })
+}
+
+/// An advertising report received while scanning.
+///
+/// This borrows the raw report passed to the [`scan`] callback, and is only valid
+/// for the duration of the callback.
+#[derive(Copy, Clone)]
+pub struct AdvReport<'a> {
+    raw: &'a raw::ble_gap_evt_adv_report_t,
+}
+
+impl<'a> AdvReport<'a> {
+    /// Wrap a raw advertising report.
+    ///
+    /// # Safety
+    ///
+    /// `raw.data` must point to `raw.data.len` valid bytes for the lifetime `'a`. This
+    /// is the case for the report passed to the [`scan`] callback.
+    pub unsafe fn from_raw(raw: &'a raw::ble_gap_evt_adv_report_t) -> Self {
+        Self { raw }
+    }
+
+    pub fn as_raw(&self) -> &'a raw::ble_gap_evt_adv_report_t {
+        self.raw
+    }
+
+    /// Address of the advertiser.
+    pub fn peer_address(&self) -> Address {
+        Address::from_raw(self.raw.peer_addr)
+    }
+
+    /// Received signal strength of the last packet, in dBm.
+    pub fn rssi(&self) -> i8 {
+        self.raw.rssi
+    }
+
+    /// TX power advertised by the peer in the extended advertising header, in dBm.
+    /// `127` if not available.
+    pub fn tx_power(&self) -> i8 {
+        self.raw.tx_power
+    }
+
+    /// Kind of advertising PDU the report was received from.
+    pub fn adv_type(&self) -> AdvReportType {
+        AdvReportType::from_raw(self.raw.type_)
+    }
+
+    /// Whether the advertising data in this report is complete.
+    pub fn data_status(&self) -> AdvDataStatus {
+        AdvDataStatus::from_raw(self.raw.type_.status())
+    }
+
+    /// PHY the advertisement was received on, on the primary advertising channel.
+    pub fn primary_phy(&self) -> Option<Phy> {
+        Phy::try_from_raw(self.raw.primary_phy)
+    }
+
+    /// PHY the advertisement was received on, on the secondary advertising channel.
+    ///
+    /// `None` for legacy advertisements, which are only sent on the primary channel.
+    pub fn secondary_phy(&self) -> Option<Phy> {
+        Phy::try_from_raw(self.raw.secondary_phy)
+    }
+
+    /// Advertising or scan response data.
+    pub fn data(&self) -> &'a [u8] {
+        if self.raw.data.p_data.is_null() {
+            return &[];
+        }
+        // Safety: guaranteed by the contract of `from_raw`.
+        unsafe { slice::from_raw_parts(self.raw.data.p_data, self.raw.data.len as usize) }
+    }
+
+    /// Iterator over the AD structures in the advertising or scan response data.
+    pub fn ad_structures(&self) -> AdvertisementDataIter<'a> {
+        AdvertisementDataIter::new(self.data())
+    }
+
+    /// Returns true if the advertising data is incomplete, either because it didn't fit in the
+    /// scan buffer or because the SoftDevice failed to receive part of it.
+    pub fn is_truncated(&self) -> bool {
+        matches!(self.data_status(), AdvDataStatus::Truncated | AdvDataStatus::Missed)
+    }
+
+    /// Connection interval range from the Peripheral Connection Interval Range AD structure, in
+    /// units of 1.25ms, or `None` if the advertisement does not contain it.
+    ///
+    /// Either bound may be `0xFFFF`, meaning the peripheral has no preference for it.
+    pub fn conn_interval_range(&self) -> Option<(u16, u16)> {
+        match *self.ad_structures().find(ad_type::PERIPHERAL_CONN_INTERVAL_RANGE)? {
+            [min0, min1, max0, max1] => Some((u16::from_le_bytes([min0, min1]), u16::from_le_bytes([max0, max1]))),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the advertising data lists `uuid` in one of its 16-bit or 128-bit service
+    /// UUID lists, complete or incomplete.
+    ///
+    /// 128-bit UUIDs only match if their base was registered with the SoftDevice, which
+    /// [`Uuid::new_128`] does.
+    pub fn advertises_service(&self, uuid: &Uuid) -> bool {
+        self.ad_structures().any(|(t, data)| {
+            let len = match t {
+                ad_type::INCOMPLETE_16BIT_SERVICE_UUIDS | ad_type::COMPLETE_16BIT_SERVICE_UUIDS => 2,
+                ad_type::INCOMPLETE_128BIT_SERVICE_UUIDS | ad_type::COMPLETE_128BIT_SERVICE_UUIDS => 16,
+                _ => return false,
+            };
+            data.chunks_exact(len).any(|bytes| decode_uuid(bytes) == Some(*uuid))
+        })
+    }
+
+    /// Target address of a directed advertisement, or `None` if the advertisement is undirected.
+    ///
+    /// If the SoftDevice was able to resolve the target address, the returned address is the
+    /// local identity address and [`Address::is_resolved_peer_id`] is set.
+    pub fn direct_address(&self) -> Option<Address> {
+        if self.raw.type_.directed() != 0 {
+            Some(Address::from_raw(self.raw.direct_addr))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if this is a directed advertisement targeting the local device.
+    ///
+    /// An unresolved resolvable private target address is never considered to be ours.
+    pub fn is_directed_to_us(&self, sd: &Softdevice) -> bool {
+        match self.direct_address() {
+            Some(addr) => addr.is_resolved_peer_id() || addr == get_address(sd),
+            None => false,
+        }
+    }
+}
+
+/// Decode a little-endian 16-bit or 128-bit UUID, or `None` if its base isn't registered.
+fn decode_uuid(bytes: &[u8]) -> Option<Uuid> {
+    let mut uuid: raw::ble_uuid_t = unsafe { mem::zeroed() };
+    let ret = unsafe { raw::sd_ble_uuid_decode(bytes.len() as u8, bytes.as_ptr(), &mut uuid) };
+    match RawError::convert(ret) {
+        Ok(()) => Uuid::from_raw(uuid),
+        Err(_) => None,
+    }
+}
+
+/// Kind of advertising PDU an advertising report was received from.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct AdvReportType {
+    pub connectable: bool,
+    pub scannable: bool,
+    pub directed: bool,
+    pub scan_response: bool,
+    pub extended_pdu: bool,
+}
+
+impl AdvReportType {
+    pub fn from_raw(raw: raw::ble_gap_adv_report_type_t) -> Self {
+        Self {
+            connectable: raw.connectable() != 0,
+            scannable: raw.scannable() != 0,
+            directed: raw.directed() != 0,
+            scan_response: raw.scan_response() != 0,
+            extended_pdu: raw.extended_pdu() != 0,
+        }
+    }
+}
+
+/// Completeness of the advertising data in an advertising report.
+///
+/// Extended advertising data can span a chain of PDUs. The SoftDevice receives the whole
+/// chain before reporting it, so a report's data is either complete, or cut short because it
+/// didn't fit or part of the chain was lost.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum AdvDataStatus {
+    /// All advertising data was received.
+    Complete,
+    /// More data is on its way, in a later report.
+    ///
+    /// Only reported when scanning with `report_incomplete_evts` set, which [`ScanConfig`]
+    /// doesn't do.
+    MoreData,
+    /// The data didn't fit in the scan buffer, and was truncated.
+    Truncated,
+    /// Part of the chain was not received.
+    Missed,
+}
+
+impl AdvDataStatus {
+    /// Convert a `BLE_GAP_ADV_DATA_STATUS_*` value.
+    pub fn from_raw(raw: u16) -> Self {
+        // Not all SoftDevice headers define the constants for the incomplete statuses.
+        match raw {
+            0 => Self::Complete,
+            1 => Self::MoreData,
+            2 => Self::Truncated,
+            _ => Self::Missed,
+        }
+    }
+}
+
+/// Scan parameters.
+///
+/// Note that none of the SoftDevice variants support controller-level filtering of duplicate
+/// advertising reports: every received report is passed to the application, so any deduplication
+/// has to be done in the [`scan`] callback.
 #[derive(Copy, Clone)]
 pub struct ScanConfig<'a> {
     /// Whitelist of addresses to scan. If None, all advertisements
     /// will be processed when scanning.
     ///
-    /// For connecting this must be Some, and have least 1 address.
+    /// For connecting this must be Some, and have least 1 address. With several addresses,
+    /// the connection is made to whichever of them is seen first; use
+    /// [`Connection::peer_address`] to tell which one it is.
+    ///
+    /// For a peer using resolvable private addresses, add its identity key to
+    /// [`identities`](Self::identities) and put its identity address here.
     pub whitelist: Option<&'a [&'a Address]>,
 
+    /// Identity keys of peers using resolvable private addresses, e.g. bonded peers with privacy
+    /// enabled. If set, this replaces the SoftDevice's device identities list, as set by
+    /// [`set_device_identities_list`](crate::ble::set_device_identities_list), before scanning.
+    ///
+    /// The SoftDevice then resolves these peers' private addresses, so they match their identity
+    /// address in the whitelist, and reports and connections carry the identity address with
+    /// [`Address::is_resolved_peer_id`] set.
+    ///
+    /// The list can't be changed while a connection to a peer in the current list is open, so
+    /// scanning fails with [`RawError::BleGapDeviceIdentitiesInUse`] in that case.
+    pub identities: Option<&'a [IdentityKey]>,
+
     /// Support extended advertisements.
     ///
     /// If true, the scanner will accept extended advertising packets.
@@ -236,7 +1083,13 @@ pub struct ScanConfig<'a> {
     /// Ignored when using for connecting.
     pub active: bool,
 
-    /// Set of PHYs to scan
+    /// Set of PHYs to scan on the primary advertising channels. This is also the set of PHYs
+    /// a connection is initiated on when used for connecting.
+    ///
+    /// Sets containing 1M and/or 2M scan on 1M, since advertising is never sent on 2M on the primary
+    /// channels. Sets containing Coded also scan on Coded; extended scanning is always enabled
+    /// for those, as the SoftDevice rejects Coded PHY without it. Without `extended`, only
+    /// 1M is accepted.
     pub phys: PhySet,
 
     /// Scan interval, in units of 625us
@@ -251,6 +1104,18 @@ pub struct ScanConfig<'a> {
     /// Radio TX power. This is used for scanning, and is inherited
     /// as the connection TX power if this ScanConfig is used for connect().
     pub tx_power: TxPower,
+
+    /// If set, reports with an RSSI below this value, in dBm, are dropped by the driver
+    /// instead of being passed to the application. Ignored when using for connecting.
+    pub min_rssi: Option<i8>,
+
+    /// If true, only the first report from each advertiser is passed to the application,
+    /// and repeated reports are dropped by the driver. The advertisement and the scan
+    /// response are tracked separately, so both are reported. Ignored when using for connecting.
+    ///
+    /// Up to [`SCAN_DEDUP_MAX`] advertisers are remembered. Past that, the oldest is forgotten
+    /// and will be reported again.
+    pub dedup: bool,
 }
 
 impl<'a> Default for ScanConfig<'a> {
@@ -263,7 +1128,10 @@ impl<'a> Default for ScanConfig<'a> {
             window: 500,
             timeout: raw::BLE_GAP_SCAN_TIMEOUT_UNLIMITED as _,
             whitelist: None,
+            identities: None,
             tx_power: TxPower::ZerodBm,
+            min_rssi: None,
+            dedup: false,
         }
     }
 }
@@ -271,7 +1139,7 @@ impl<'a> Default for ScanConfig<'a> {
 impl<'a> ScanConfig<'a> {
     fn to_raw(&self) -> Result<raw::ble_gap_scan_params_t, RawError> {
         let mut scan_params: raw::ble_gap_scan_params_t = unsafe { mem::zeroed() };
-        if self.extended {
+        if self.extended || self.phys as u8 & raw::BLE_GAP_PHY_CODED as u8 != 0 {
             scan_params.set_extended(1);
         }
         if self.active {
@@ -292,6 +1160,14 @@ impl<'a> ScanConfig<'a> {
             scan_params.window_us = self.window * 625;
         }
 
+        // Set device identities, before the whitelist that may refer to them
+        if let Some(ids) = self.identities {
+            if let Err(err) = crate::ble::gap::device_identities_set(ids, None) {
+                warn!("sd_ble_gap_device_identities_set err {:?}", err);
+                return Err(err);
+            }
+        }
+
         // Set whitelist
         if let Some(w) = self.whitelist {
             assert!(w.len() <= u8::MAX as usize);
@@ -1,12 +1,16 @@
 use core::cell::{Cell, UnsafeCell};
 use core::iter::FusedIterator;
+use core::task::Poll;
+
+use futures::future::poll_fn;
 
 use raw::ble_gap_conn_params_t;
 
 use super::PhySet;
 #[cfg(feature = "ble-sec")]
 use crate::ble::security::SecurityHandler;
-use crate::ble::types::{Address, AddressType, Role, SecurityMode};
+use crate::ble::types::{Address, AddressType, HciStatus, Role, SecurityMode};
+use crate::util::{get_union_field, Portal, WakerSet};
 use crate::{raw, RawError};
 
 #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
@@ -65,6 +69,29 @@ impl From<RawError> for IgnoreSlaveLatencyError {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum SetAuthPayloadTimeoutError {
+    Disconnected,
+    Raw(RawError),
+}
+
+impl From<DisconnectedError> for SetAuthPayloadTimeoutError {
+    fn from(_err: DisconnectedError) -> Self {
+        Self::Disconnected
+    }
+}
+
+impl From<RawError> for SetAuthPayloadTimeoutError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum PhyUpdateError {
     Disconnected,
     Raw(RawError),
@@ -82,9 +109,167 @@ impl From<RawError> for PhyUpdateError {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+pub enum DataLengthUpdateError {
+    Disconnected,
+    Raw(RawError),
+}
+
+#[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+impl From<DisconnectedError> for DataLengthUpdateError {
+    fn from(_err: DisconnectedError) -> Self {
+        Self::Disconnected
+    }
+}
+
+#[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+impl From<RawError> for DataLengthUpdateError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
+/// Decides how a central answers a peripheral's connection param update request.
+///
+/// Called with the params requested by the peer. Return the params to apply (usually the requested
+/// ones, possibly adjusted to fit the requested range), or `None` to reject the request.
+#[cfg(feature = "ble-central")]
+pub type ConnParamRequestHandler = fn(&ble_gap_conn_params_t) -> Option<ble_gap_conn_params_t>;
+
+/// HCI reason code sent to the peer when disconnecting. These are the only
+/// values the SoftDevice accepts.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum DisconnectReason {
+    #[default]
+    RemoteUserTerminatedConnection,
+    ConnIntervalUnacceptable,
+}
+
+impl DisconnectReason {
+    fn to_raw(self) -> u8 {
+        match self {
+            DisconnectReason::RemoteUserTerminatedConnection => raw::BLE_HCI_REMOTE_USER_TERMINATED_CONNECTION as u8,
+            DisconnectReason::ConnIntervalUnacceptable => raw::BLE_HCI_CONN_INTERVAL_UNACCEPTABLE as u8,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum ConnParamsError {
+    /// A connection interval is outside 7.5ms..=4s.
+    IntervalOutOfRange,
+    /// No valid connection interval lies between the minimum and the maximum.
+    IntervalRangeEmpty,
+    /// The slave latency is above 499 connection events.
+    SlaveLatencyTooHigh,
+    /// The supervision timeout is outside 100ms..=32s.
+    SupervisionTimeoutOutOfRange,
+    /// The supervision timeout must be longer than `(1 + slave_latency) * max_interval * 2`.
+    SupervisionTimeoutTooShort,
+}
+
+/// Connection parameters, validated and converted from physical units.
+///
+/// `ble_gap_conn_params_t` counts connection intervals in 1.25ms units and the supervision
+/// timeout in 10ms units. Convert with `.into()` wherever one is expected, such as
+/// [`Connection::set_conn_params`] or `ConnectConfig::conn_params`.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnParams {
+    raw: ble_gap_conn_params_t,
+}
+
+impl ConnParams {
+    /// Connection parameters with the intervals and supervision timeout in milliseconds.
+    ///
+    /// Use [`ConnParams::from_micros`] for intervals that are not a whole number of
+    /// milliseconds, such as the 7.5ms minimum.
+    pub fn from_millis(
+        min_interval_ms: u32,
+        max_interval_ms: u32,
+        slave_latency: u16,
+        sup_timeout_ms: u32,
+    ) -> Result<Self, ConnParamsError> {
+        Self::from_micros(
+            min_interval_ms.saturating_mul(1000),
+            max_interval_ms.saturating_mul(1000),
+            slave_latency,
+            sup_timeout_ms,
+        )
+    }
+
+    /// Connection parameters with the intervals in microseconds and the supervision timeout
+    /// in milliseconds.
+    ///
+    /// The intervals are rounded inwards to the 1.25ms grid, and the supervision timeout up
+    /// to the 10ms grid.
+    pub fn from_micros(
+        min_interval_us: u32,
+        max_interval_us: u32,
+        slave_latency: u16,
+        sup_timeout_ms: u32,
+    ) -> Result<Self, ConnParamsError> {
+        let min = (min_interval_us.saturating_add(1249) / 1250) as u64;
+        let max = (max_interval_us / 1250) as u64;
+        let timeout = (sup_timeout_ms.saturating_add(9) / 10) as u64;
+
+        let interval_range = raw::BLE_GAP_CP_MIN_CONN_INTVL_MIN as u64..=raw::BLE_GAP_CP_MAX_CONN_INTVL_MAX as u64;
+        if !interval_range.contains(&min) || !interval_range.contains(&max) {
+            return Err(ConnParamsError::IntervalOutOfRange);
+        }
+        if min > max {
+            return Err(ConnParamsError::IntervalRangeEmpty);
+        }
+        if slave_latency as u32 > raw::BLE_GAP_CP_SLAVE_LATENCY_MAX {
+            return Err(ConnParamsError::SlaveLatencyTooHigh);
+        }
+        let timeout_range = raw::BLE_GAP_CP_CONN_SUP_TIMEOUT_MIN as u64..=raw::BLE_GAP_CP_CONN_SUP_TIMEOUT_MAX as u64;
+        if !timeout_range.contains(&timeout) {
+            return Err(ConnParamsError::SupervisionTimeoutOutOfRange);
+        }
+        // timeout * 10ms > (1 + latency) * max * 1.25ms * 2
+        if timeout * 4 <= (1 + slave_latency as u64) * max {
+            return Err(ConnParamsError::SupervisionTimeoutTooShort);
+        }
+
+        Ok(Self {
+            raw: ble_gap_conn_params_t {
+                min_conn_interval: min as u16,
+                max_conn_interval: max as u16,
+                slave_latency,
+                conn_sup_timeout: timeout as u16,
+            },
+        })
+    }
+
+    /// The parameters in SoftDevice units, the same as converting with `.into()`.
+    pub fn to_raw(&self) -> ble_gap_conn_params_t {
+        self.raw
+    }
+}
+
+impl From<ConnParams> for ble_gap_conn_params_t {
+    fn from(params: ConnParams) -> Self {
+        params.raw
+    }
+}
+
 // Highest ever the softdevice can support.
 pub(crate) const CONNS_MAX: usize = 20;
 
+// Number of tasks per connection that can be in `Connection::wait_disconnected` at once.
+const DISCONNECT_WAKERS_MAX: usize = 4;
+
+// Number of L2CAP channels per connection tracked for `L2cap::channels`.
+#[cfg(feature = "ble-l2cap")]
+pub(crate) const L2CAP_CHANNELS_MAX: usize = 4;
+
 #[cfg(feature = "ble-sec")]
 #[derive(Clone, Copy)]
 pub(crate) struct EncryptionState {
@@ -142,11 +327,25 @@ pub(crate) struct ConnectionState {
     pub conn_handle: Option<u16>,
 
     pub disconnecting: bool,
+    // HCI reason of the disconnection, once conn_handle is None.
+    pub disconnect_reason: u8,
+    // Tasks in `Connection::wait_disconnected`.
+    pub disconnect_wakers: WakerSet<DISCONNECT_WAKERS_MAX>,
     pub role: Role,
     pub peer_address: Address,
     pub security_mode: SecurityMode,
 
     pub conn_params: ble_gap_conn_params_t,
+    // Last connection params requested by the peripheral.
+    #[cfg(feature = "ble-central")]
+    pub peer_conn_params: Option<ble_gap_conn_params_t>,
+    // Decides how to answer the peripheral's connection param requests. None accepts them as-is.
+    #[cfg(feature = "ble-central")]
+    pub conn_param_request_handler: Option<ConnParamRequestHandler>,
+
+    // Active PHYs, as raw BLE_GAP_PHY_* values.
+    pub tx_phy: u8,
+    pub rx_phy: u8,
 
     #[cfg(feature = "ble-rssi")]
     pub rssi: Option<i8>,
@@ -158,6 +357,9 @@ pub(crate) struct ConnectionState {
 
     #[cfg(feature = "ble-sec")]
     pub security: EncryptionState,
+
+    #[cfg(feature = "ble-l2cap")]
+    pub l2cap_channels: heapless::Vec<crate::ble::l2cap::ChannelState, L2CAP_CHANNELS_MAX>,
 }
 
 impl ConnectionState {
@@ -174,12 +376,20 @@ impl ConnectionState {
             peer_address: Address::new(AddressType::Public, [0; 6]),
             security_mode: SecurityMode::NoAccess,
             disconnecting: false,
+            disconnect_reason: 0,
+            disconnect_wakers: WakerSet::new(),
             conn_params: ble_gap_conn_params_t {
                 conn_sup_timeout: 0,
                 max_conn_interval: 0,
                 min_conn_interval: 0,
                 slave_latency: 0,
             },
+            #[cfg(feature = "ble-central")]
+            peer_conn_params: None,
+            #[cfg(feature = "ble-central")]
+            conn_param_request_handler: None,
+            tx_phy: 0,
+            rx_phy: 0,
             #[cfg(feature = "ble-rssi")]
             rssi: None,
             #[cfg(feature = "ble-gatt")]
@@ -188,6 +398,8 @@ impl ConnectionState {
             data_length_effective: 0,
             #[cfg(feature = "ble-sec")]
             security: NEW_ENCRYPTION_STATE,
+            #[cfg(feature = "ble-l2cap")]
+            l2cap_channels: heapless::Vec::new(),
         }
     }
     pub(crate) fn check_connected(&mut self) -> Result<u16, DisconnectedError> {
@@ -195,21 +407,24 @@ impl ConnectionState {
     }
 
     pub(crate) fn disconnect(&mut self) -> Result<(), DisconnectedError> {
+        self.disconnect_with_reason(DisconnectReason::default())
+    }
+
+    pub(crate) fn disconnect_with_reason(&mut self, reason: DisconnectReason) -> Result<(), DisconnectedError> {
         let conn_handle = self.check_connected()?;
 
         if self.disconnecting {
             return Ok(());
         }
 
-        let ret =
-            unsafe { raw::sd_ble_gap_disconnect(conn_handle, raw::BLE_HCI_REMOTE_USER_TERMINATED_CONNECTION as u8) };
+        let ret = unsafe { raw::sd_ble_gap_disconnect(conn_handle, reason.to_raw()) };
         unwrap!(RawError::convert(ret), "sd_ble_gap_disconnect");
 
         self.disconnecting = true;
         Ok(())
     }
 
-    pub(crate) fn on_disconnected(&mut self, _ble_evt: *const raw::ble_evt_t) {
+    pub(crate) fn on_disconnected(&mut self, ble_evt: *const raw::ble_evt_t) {
         let conn_handle = unwrap!(self.conn_handle, "bug: on_disconnected when already disconnected");
 
         let ibh = index_by_handle(conn_handle);
@@ -224,14 +439,23 @@ impl ConnectionState {
         ibh.set(None);
 
         self.conn_handle = None;
+        let gap_evt = unsafe { get_union_field(ble_evt, &(*ble_evt).evt.gap_evt) };
+        self.disconnect_reason = unsafe { gap_evt.params.disconnected.reason };
+
+        // Also frees the received packets nobody picked up.
+        #[cfg(feature = "ble-l2cap")]
+        self.l2cap_channels.clear();
 
         // Signal possible in-progess operations that the connection has disconnected.
         #[cfg(feature = "ble-gatt-client")]
-        crate::ble::gatt_client::portal(conn_handle).call(_ble_evt);
+        crate::ble::gatt_client::portal(conn_handle).call(ble_evt);
         #[cfg(feature = "ble-gatt-server")]
-        crate::ble::gatt_server::portal(conn_handle).call(_ble_evt);
+        crate::ble::gatt_server::portal(conn_handle).call(ble_evt);
         #[cfg(feature = "ble-l2cap")]
-        crate::ble::l2cap::portal(conn_handle).call(_ble_evt);
+        crate::ble::l2cap::portal(conn_handle).call(ble_evt);
+
+        // Wake tasks in `Connection::wait_disconnected`.
+        self.disconnect_wakers.wake();
 
         trace!("conn {:?}: disconnected", _index);
     }
@@ -312,6 +536,11 @@ impl Connection {
         self.with_state(|state| state.role)
     }
 
+    /// Get the peer's address, as reported when the connection was established.
+    ///
+    /// If the SoftDevice resolved the peer's private address using the device identities list,
+    /// this is the peer's identity address, with [`Address::is_resolved_peer_id`] set. It stays
+    /// available after disconnection.
     pub fn peer_address(&self) -> Address {
         self.with_state(|state| state.peer_address)
     }
@@ -320,6 +549,18 @@ impl Connection {
         self.with_state(|state| state.disconnect())
     }
 
+    /// Start disconnecting, sending `reason` to the peer.
+    ///
+    /// Like [`disconnect`](Self::disconnect), this doesn't wait for the disconnection to complete.
+    /// Nothing is sent if a disconnection is already in progress.
+    pub fn disconnect_with_reason(&self, reason: DisconnectReason) -> Result<(), DisconnectedError> {
+        self.with_state(|state| state.disconnect_with_reason(reason))
+    }
+
+    /// Get the SoftDevice connection handle, or `None` if disconnected.
+    ///
+    /// This is the `conn_handle` to pass to `raw` functions not wrapped by this crate. The same
+    /// handle may be reused for a later connection once this one is disconnected.
     pub fn handle(&self) -> Option<u16> {
         self.with_state(|state| state.conn_handle)
     }
@@ -349,8 +590,18 @@ impl Connection {
                 security_mode: SecurityMode::Open,
 
                 disconnecting: false,
+                disconnect_reason: 0,
+                disconnect_wakers: WakerSet::new(),
 
                 conn_params,
+                #[cfg(feature = "ble-central")]
+                peer_conn_params: None,
+                #[cfg(feature = "ble-central")]
+                conn_param_request_handler: None,
+
+                // Connections are always established on 1M, until a PHY update procedure completes.
+                tx_phy: raw::BLE_GAP_PHY_1MBPS as u8,
+                rx_phy: raw::BLE_GAP_PHY_1MBPS as u8,
 
                 #[cfg(feature = "ble-rssi")]
                 rssi: None,
@@ -363,6 +614,9 @@ impl Connection {
 
                 #[cfg(feature = "ble-sec")]
                 security: NEW_ENCRYPTION_STATE,
+
+                #[cfg(feature = "ble-l2cap")]
+                l2cap_channels: heapless::Vec::new(),
             };
 
             // Update index_by_handle
@@ -413,6 +667,36 @@ impl Connection {
         with_state(self.index, |s| s.conn_params)
     }
 
+    /// Get the connection params last requested by the peer, if any.
+    ///
+    /// A peripheral requests its preferred params with an L2CAP connection parameter update
+    /// request. The SoftDevice does not report when the peer rejects params set with
+    /// [`set_conn_params`][Self::set_conn_params], so this is the only indication of the range
+    /// the peer accepts.
+    #[cfg(feature = "ble-central")]
+    pub fn peer_conn_params(&self) -> Option<ble_gap_conn_params_t> {
+        with_state(self.index, |s| s.peer_conn_params)
+    }
+
+    /// Set how connection param update requests from the peer are answered.
+    ///
+    /// By default (or with `None`) requests are accepted as-is. Otherwise `handler` is called
+    /// for each request, and either picks the params to apply or rejects the request.
+    #[cfg(feature = "ble-central")]
+    pub fn set_conn_param_request_handler(&self, handler: Option<ConnParamRequestHandler>) {
+        with_state(self.index, |s| s.conn_param_request_handler = handler)
+    }
+
+    /// Get the PHY this device currently transmits on.
+    pub fn tx_phy(&self) -> Phy {
+        with_state(self.index, |s| Phy::try_from_raw(s.tx_phy).unwrap_or(Phy::M1))
+    }
+
+    /// Get the PHY this device currently receives on.
+    pub fn rx_phy(&self) -> Phy {
+        with_state(self.index, |s| Phy::try_from_raw(s.rx_phy).unwrap_or(Phy::M1))
+    }
+
     /// Get the currently active ATT MTU.
     #[cfg(feature = "ble-gatt")]
     pub fn att_mtu(&self) -> u16 {
@@ -423,6 +707,14 @@ impl Connection {
         with_state(self.index, |s| s.security_mode)
     }
 
+    /// Returns true if the link is currently encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        matches!(
+            self.security_mode(),
+            SecurityMode::JustWorks | SecurityMode::Mitm | SecurityMode::LescMitm
+        )
+    }
+
     #[cfg(feature = "ble-sec")]
     pub fn security_handler(&self) -> Option<&dyn SecurityHandler> {
         with_state(self.index, |s| s.security.handler)
@@ -489,20 +781,79 @@ impl Connection {
         Ok(())
     }
 
+    /// Set the authenticated payload timeout, in 10ms units.
+    ///
+    /// The SoftDevice does not expose an on-demand LE Ping. Instead, on an encrypted link, it runs
+    /// the LE Ping procedure on its own whenever no packet with a valid MIC has been received for
+    /// close to `timeout`. If the timeout elapses anyway, the SoftDevice reports a GAP timeout
+    /// event with source `BLE_GAP_TIMEOUT_SRC_AUTH_PAYLOAD`, which the driver only logs: it isn't
+    /// passed to the application, and the link stays up until it's disconnected or the
+    /// supervision timeout expires. This is supported by all SoftDevice variants, but has no
+    /// effect on unencrypted links.
+    ///
+    /// `timeout` must be between `BLE_GAP_AUTH_PAYLOAD_TIMEOUT_MIN` and `BLE_GAP_AUTH_PAYLOAD_TIMEOUT_MAX`
+    /// (the default). Nordic recommends a value larger than `2 * conn_interval * (6 + slave_latency)`.
+    pub fn set_auth_payload_timeout(&self, timeout: u16) -> Result<(), SetAuthPayloadTimeoutError> {
+        let conn_handle = self.with_state(|state| state.check_connected())?;
+
+        let ret = unsafe {
+            raw::sd_ble_opt_set(
+                raw::BLE_GAP_OPTS_BLE_GAP_OPT_AUTH_PAYLOAD_TIMEOUT,
+                &raw::ble_opt_t {
+                    gap_opt: raw::ble_gap_opt_t {
+                        auth_payload_timeout: raw::ble_gap_opt_auth_payload_timeout_t {
+                            conn_handle,
+                            auth_payload_timeout: timeout,
+                        },
+                    },
+                },
+            )
+        };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("set_auth_payload_timeout sd_ble_opt_set err {:?}", err);
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn with_state<T>(&self, f: impl FnOnce(&mut ConnectionState) -> T) -> T {
         with_state(self.index, f)
     }
 
+    /// Wait until the connection is disconnected, returning the reason.
+    ///
+    /// If the connection is already disconnected, this returns immediately.
+    ///
+    /// At most 4 tasks can wait on the same connection at the same time. With more, they keep
+    /// waking each other up.
+    pub async fn wait_disconnected(&self) -> HciStatus {
+        poll_fn(|cx| {
+            self.with_state(|state| {
+                if state.conn_handle.is_none() {
+                    return Poll::Ready(HciStatus(state.disconnect_reason));
+                }
+
+                state.disconnect_wakers.register(cx.waker());
+                Poll::Pending
+            })
+        })
+        .await
+    }
+
     pub fn iter() -> ConnectionIter {
         ConnectionIter(0)
     }
 
     /// Send a request to the connected device to change the PHY.
     ///
+    /// `tx_phys` and `rx_phys` are the PHYs this device prefers to transmit and receive on, respectively.
+    ///
     /// Note that this just initiates the PHY change, it does not wait for completion.
     /// Immediately after return, the active PHYs will still be the old ones, and after some time
-    /// they should change to the new ones.
-    pub fn phy_update(&mut self, tx_phys: PhySet, rx_phys: PhySet) -> Result<(), PhyUpdateError> {
+    /// they should change to the new ones. Use [`Connection::tx_phy`] and [`Connection::rx_phy`]
+    /// to get the PHYs in use.
+    pub fn phy_update(&self, tx_phys: PhySet, rx_phys: PhySet) -> Result<(), PhyUpdateError> {
         let conn_handle = self.with_state(|state| state.check_connected())?;
         let p_gap_phys = raw::ble_gap_phys_t {
             tx_phys: tx_phys as u8,
@@ -516,6 +867,42 @@ impl Connection {
 
         Ok(())
     }
+
+    /// Start a data length update procedure, requesting the given maximum link layer payload
+    /// sizes, in octets (27 to 251). `None` lets the SoftDevice pick the largest size it supports.
+    ///
+    /// Note that this just initiates the procedure, it does not wait for completion. Use
+    /// [`Connection::data_length`] to get the data length in effect.
+    #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+    pub fn data_length_update(
+        &self,
+        tx_octets: Option<u16>,
+        rx_octets: Option<u16>,
+    ) -> Result<(), DataLengthUpdateError> {
+        let conn_handle = self.with_state(|state| state.check_connected())?;
+        let params = raw::ble_gap_data_length_params_t {
+            max_tx_octets: tx_octets.unwrap_or(raw::BLE_GAP_DATA_LENGTH_AUTO as u16),
+            max_rx_octets: rx_octets.unwrap_or(raw::BLE_GAP_DATA_LENGTH_AUTO as u16),
+            max_tx_time_us: raw::BLE_GAP_DATA_LENGTH_AUTO as u16,
+            max_rx_time_us: raw::BLE_GAP_DATA_LENGTH_AUTO as u16,
+        };
+        unsafe { crate::ble::gap::do_data_length_update(conn_handle, &params) }?;
+        Ok(())
+    }
+
+    /// Exchange the ATT MTU with the peer, offering `mtu`, and return the ATT MTU in effect.
+    ///
+    /// See [`gatt_client::att_mtu_exchange`](crate::ble::gatt_client::att_mtu_exchange).
+    #[cfg(feature = "ble-gatt-client")]
+    pub async fn exchange_mtu(&self, mtu: u16) -> Result<u16, crate::ble::gatt_client::MtuExchangeError> {
+        crate::ble::gatt_client::att_mtu_exchange(self, mtu).await
+    }
+
+    /// Maximum link layer payload size for transmission currently in effect, in octets.
+    #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+    pub fn data_length(&self) -> u8 {
+        self.with_state(|state| state.data_length_effective)
+    }
 }
 
 pub struct ConnectionIter(u8);
@@ -562,6 +949,24 @@ pub(crate) fn with_state_by_conn_handle<T>(conn_handle: u16, f: impl FnOnce(&mut
     with_state(index, f)
 }
 
+pub(crate) fn try_with_state_by_conn_handle<T>(
+    conn_handle: u16,
+    f: impl FnOnce(&mut ConnectionState) -> T,
+) -> Option<T> {
+    let index = index_by_handle(conn_handle).get()?;
+    Some(with_state(index, f))
+}
+
+/// Get the portal of `conn_handle` out of `portals`, one per connection handle, or `None` if
+/// the handle is out of range. For handles coming straight from SoftDevice events.
+pub(crate) fn try_portal<T>(portals: &'static [Portal<T>], conn_handle: u16) -> Option<&'static Portal<T>> {
+    let res = portals.get(conn_handle as usize);
+    if res.is_none() {
+        warn!("ignoring event for out of range conn_handle {:?}", conn_handle);
+    }
+    res
+}
+
 pub(crate) fn with_state<T>(index: u8, f: impl FnOnce(&mut ConnectionState) -> T) -> T {
     let state = unsafe { &mut *STATES[index as usize].get() };
     f(state)
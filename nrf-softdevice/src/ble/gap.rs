@@ -59,7 +59,23 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                 conn_params.slave_latency,
             );
 
-            let ret = raw::sd_ble_gap_conn_param_update(conn_handle, &conn_params);
+            let handler = connection::with_state_by_conn_handle(conn_handle, |state| {
+                state.peer_conn_params = Some(conn_params);
+                state.conn_param_request_handler
+            });
+
+            // A null pointer rejects the request.
+            let reply = match handler {
+                Some(handler) => handler(&conn_params),
+                None => Some(conn_params),
+            };
+            let ret = match &reply {
+                Some(params) => raw::sd_ble_gap_conn_param_update(conn_handle, params),
+                None => {
+                    debug!("rejecting conn param update request conn_handle={:?}", conn_handle);
+                    raw::sd_ble_gap_conn_param_update(conn_handle, core::ptr::null())
+                }
+            };
             if let Err(err) = RawError::convert(ret) {
                 warn!("sd_ble_gap_conn_param_update err {:?}", err);
             }
@@ -70,9 +86,17 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
             let params = &gap_evt.params.timeout;
             match params.src as u32 {
                 #[cfg(feature = "ble-central")]
-                raw::BLE_GAP_TIMEOUT_SRC_CONN => central::CONNECT_PORTAL.call(ble_evt),
+                raw::BLE_GAP_TIMEOUT_SRC_CONN => {
+                    central::CONNECT_PORTAL.call(ble_evt);
+                }
                 #[cfg(feature = "ble-central")]
-                raw::BLE_GAP_TIMEOUT_SRC_SCAN => central::SCAN_PORTAL.call(ble_evt),
+                raw::BLE_GAP_TIMEOUT_SRC_SCAN => {
+                    central::SCAN_PORTAL.call(ble_evt);
+                }
+                // Enabled by `Connection::set_auth_payload_timeout`. The link stays up.
+                raw::BLE_GAP_TIMEOUT_SRC_AUTH_PAYLOAD => {
+                    warn!("authenticated payload timeout conn_handle={:?}", gap_evt.conn_handle);
+                }
                 x => panic!("unknown timeout src {:?}", x),
             };
         }
@@ -86,6 +110,11 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
             trace!("central on_adv_report");
             central::SCAN_PORTAL.call(ble_evt);
         }
+        #[cfg(all(feature = "ble-central", any(feature = "s132", feature = "s140")))]
+        raw::BLE_GAP_EVTS_BLE_GAP_EVT_QOS_CHANNEL_SURVEY_REPORT => {
+            trace!("central on_qos_channel_survey_report");
+            central::SURVEY_PORTAL.call(ble_evt);
+        }
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_PHY_UPDATE_REQUEST => {
             let peer_preferred_phys = gap_evt.params.phy_update_request.peer_preferred_phys;
             let conn_handle = gap_evt.conn_handle;
@@ -109,15 +138,22 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
             }
         }
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_PHY_UPDATE => {
-            let _phy_update = gap_evt.params.phy_update;
+            let phy_update = gap_evt.params.phy_update;
 
             trace!(
                 "on_phy_update conn_handle={:?} status={:?} rx_phy={:?} tx_phy={:?}",
                 gap_evt.conn_handle,
-                _phy_update.status,
-                _phy_update.rx_phy,
-                _phy_update.tx_phy
+                phy_update.status,
+                phy_update.rx_phy,
+                phy_update.tx_phy
             );
+
+            if phy_update.status as u32 == raw::BLE_HCI_STATUS_CODE_SUCCESS {
+                connection::with_state_by_conn_handle(gap_evt.conn_handle, |state| {
+                    state.tx_phy = phy_update.tx_phy;
+                    state.rx_phy = phy_update.rx_phy;
+                });
+            }
         }
         #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_DATA_LENGTH_UPDATE_REQUEST => {
@@ -133,7 +169,7 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
             );
 
             let conn_handle = gap_evt.conn_handle;
-            do_data_length_update(conn_handle, core::ptr::null());
+            let _ = do_data_length_update(conn_handle, core::ptr::null());
         }
         #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_DATA_LENGTH_UPDATE => {
@@ -330,17 +366,19 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
         // BLE_GAP_EVTS_BLE_GAP_EVT_SEC_REQUEST (Peripheral-initiated security request)
         // BLE_GAP_EVTS_BLE_GAP_EVT_RSSI_CHANGED
         // BLE_GAP_EVTS_BLE_GAP_EVT_SCAN_REQ_REPORT
-        // BLE_GAP_EVTS_BLE_GAP_EVT_QOS_CHANNEL_SURVEY_REPORT
         _ => {}
     }
 }
 
 #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
-pub(crate) unsafe fn do_data_length_update(conn_handle: u16, params: *const raw::ble_gap_data_length_params_t) {
+pub(crate) unsafe fn do_data_length_update(
+    conn_handle: u16,
+    params: *const raw::ble_gap_data_length_params_t,
+) -> Result<(), RawError> {
     let mut dl_limitation = core::mem::zeroed();
     let ret = raw::sd_ble_gap_data_length_update(conn_handle, params, &mut dl_limitation);
-    if let Err(_err) = RawError::convert(ret) {
-        warn!("sd_ble_gap_data_length_update err {:?}", _err);
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_ble_gap_data_length_update err {:?}", err);
 
         if dl_limitation.tx_payload_limited_octets != 0 || dl_limitation.rx_payload_limited_octets != 0 {
             warn!(
@@ -355,7 +393,11 @@ pub(crate) unsafe fn do_data_length_update(conn_handle: u16, params: *const raw:
                 dl_limitation.tx_rx_time_limited_us
             );
         }
+
+        return Err(err);
     }
+
+    Ok(())
 }
 
 pub fn set_device_identities_list(
@@ -364,6 +406,13 @@ pub fn set_device_identities_list(
     local_irks: Option<&[IdentityResolutionKey]>,
 ) -> Result<(), RawError> {
     let _ = sd;
+    device_identities_set(id_keys, local_irks)
+}
+
+pub(crate) fn device_identities_set(
+    id_keys: &[IdentityKey],
+    local_irks: Option<&[IdentityResolutionKey]>,
+) -> Result<(), RawError> {
     const MAX_LEN: usize = raw::BLE_GAP_DEVICE_IDENTITIES_MAX_COUNT as usize;
     assert!(id_keys.len() <= MAX_LEN);
     assert!(local_irks.map(|x| x.len() == id_keys.len()).unwrap_or(true));
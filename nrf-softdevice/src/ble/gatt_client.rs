@@ -532,7 +532,9 @@ unsafe fn check_status(ble_evt: *const raw::ble_evt_t) -> Result<&'static raw::b
 
 pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
     let gattc_evt = get_union_field(ble_evt, &(*ble_evt).evt.gattc_evt);
-    portal(gattc_evt.conn_handle).call(ble_evt);
+    if let Some(portal) = connection::try_portal(&PORTALS, gattc_evt.conn_handle) {
+        portal.call(ble_evt);
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -563,8 +565,12 @@ impl From<RawError> for MtuExchangeError {
     }
 }
 
-#[cfg(feature = "ble-central")]
-pub(crate) async fn att_mtu_exchange(conn: &Connection, mtu: u16) -> Result<(), MtuExchangeError> {
+/// Exchange the ATT MTU with the peer, offering `mtu`, and return the ATT MTU in effect.
+///
+/// Nothing is done if the ATT MTU is already at least `mtu`. The exchange can only be done
+/// once per connection, further requests fail with [`RawError::InvalidState`]. `mtu` must not
+/// exceed the `att_mtu` the SoftDevice was configured with.
+pub async fn att_mtu_exchange(conn: &Connection, mtu: u16) -> Result<u16, MtuExchangeError> {
     let conn_handle = conn.with_state(|state| state.check_connected())?;
 
     let current_mtu = conn.with_state(|state| state.att_mtu);
@@ -574,7 +580,7 @@ pub(crate) async fn att_mtu_exchange(conn: &Connection, mtu: u16) -> Result<(),
             "att mtu exchange: want mtu {:?}, already got {:?}. Doing nothing.",
             mtu, current_mtu
         );
-        return Ok(());
+        return Ok(current_mtu);
     }
 
     debug!(
@@ -598,11 +604,12 @@ pub(crate) async fn att_mtu_exchange(conn: &Connection, mtu: u16) -> Result<(),
                         Err(e) => return Err(e.into()),
                     };
                     let params = get_union_field(ble_evt, &gattc_evt.params.exchange_mtu_rsp);
-                    let mtu = params.server_rx_mtu;
+                    // The ATT MTU in effect is the smaller of both sides' receive MTUs.
+                    let mtu = params.server_rx_mtu.min(mtu);
                     debug!("att mtu exchange: got mtu {:?}", mtu);
                     conn.with_state(|state| state.att_mtu = mtu);
 
-                    Ok(())
+                    Ok(mtu)
                 }
                 e => panic!("unexpected event {}", e),
             }
@@ -474,7 +474,9 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
             });
         }
         _ => {
-            portal(gatts_evt.conn_handle).call(ble_evt);
+            if let Some(portal) = connection::try_portal(&PORTALS, gatts_evt.conn_handle) {
+                portal.call(ble_evt);
+            }
         }
     }
 }
@@ -8,21 +8,32 @@
 //! of messages they can send. Only if the receive buffer has enough space
 //! more credits will be issued to the peer. Otherwise the peer has to wait
 //! before it can send more messages.
+//!
+//! With [`Config::auto_credits`] set, the SoftDevice stops issuing credits on
+//! its own and the driver grants the peer exactly one credit every time a
+//! receive buffer is handed to the SoftDevice, so credits always match the
+//! buffers actually available.
 
+use core::cell::UnsafeCell;
+use core::future::Future;
 use core::marker::PhantomData;
 use core::ptr::NonNull;
-use core::sync::atomic::{AtomicBool, Ordering};
-use core::{ptr, u16};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll};
+use core::{ptr, slice, u16};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::Sender;
+use futures::future::poll_fn;
+use futures::pin_mut;
 
 use crate::ble::*;
-use crate::util::{get_union_field, Portal};
+use crate::util::{get_union_field, Portal, WakerSet};
 use crate::{raw, RawError, Softdevice};
 
-#[cfg(feature = "ble-l2cap-credit-wrokaround")]
-fn credit_hack_refill(conn: u16, cid: u16) {
-    const CREDITS_MAX: u16 = 0xFFFF;
-    const CREDITS_MIN: u16 = 1024;
-
+#[cfg(feature = "ble-l2cap-credit-workaround")]
+fn credit_hack_refill(conn: u16, cid: u16, (credits_min, credits_max): (u16, u16)) {
     let mut credits = 0;
     let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn, cid, 0, &mut credits) };
     if let Err(err) = RawError::convert(ret) {
@@ -31,16 +42,40 @@ fn credit_hack_refill(conn: u16, cid: u16) {
     }
     trace!("sd_ble_l2cap_ch_flow_control credits={=u16:x}", credits);
 
-    if credits > CREDITS_MIN {
+    if credits > credits_min {
         // Still enough credits, no need to refill.
         return;
     }
 
     debug!("refilling credits");
 
-    let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn, cid, CREDITS_MAX, ptr::null_mut()) };
+    let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn, cid, credits_max, ptr::null_mut()) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_ble_l2cap_ch_flow_control credits={:?} err {:?}", credits_max, err);
+        return;
+    }
+
+    let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn, cid, 0, ptr::null_mut()) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_ble_l2cap_ch_flow_control credits=0 err {:?}", err);
+    }
+}
+
+#[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+fn grant_credit(conn: u16, cid: u16) {
+    // Setting credits to 0 stops the SoftDevice from issuing credits on its own,
+    // and returns the number of credits the peer currently has.
+    let mut credits = 0;
+    let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn, cid, 0, &mut credits) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_ble_l2cap_ch_flow_control credits query err {:?}", err);
+        return;
+    }
+
+    let credits = credits.saturating_add(1);
+    let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn, cid, credits, ptr::null_mut()) };
     if let Err(err) = RawError::convert(ret) {
-        warn!("sd_ble_l2cap_ch_flow_control credits=CREDITS_MAX err {:?}", err);
+        warn!("sd_ble_l2cap_ch_flow_control credits={:?} err {:?}", credits, err);
         return;
     }
 
@@ -50,6 +85,19 @@ fn credit_hack_refill(conn: u16, cid: u16) {
     }
 }
 
+/// A packet received into a buffer handed to the SoftDevice, not yet returned by `Channel::rx`.
+pub(crate) struct ReceivedSdu {
+    ptr: NonNull<u8>,
+    len: u16,
+}
+
+/// Maximum number of receive buffers a channel has handed to the SoftDevice or queued
+/// with a received packet, together.
+pub(crate) const RX_QUEUE_LEN: usize = 8;
+
+/// Number of tasks that can wait on a channel at once, see `ChannelState::wakers`.
+const CHANNEL_WAKERS_MAX: usize = 4;
+
 pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
     let l2cap_evt = get_union_field(ble_evt, &(*ble_evt).evt.l2cap_evt);
     match (*ble_evt).header.evt_id as u32 {
@@ -57,26 +105,89 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
         raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_SDU_BUF_RELEASED => {
             let params = &l2cap_evt.params.ch_sdu_buf_released;
             let pkt = unwrap!(NonNull::new(params.sdu_buf.p_data));
-            (unwrap!(PACKET_FREE))(pkt)
+            update_stats(l2cap_evt.conn_handle, l2cap_evt.local_cid, |stats| {
+                stats.bufs_released = stats.bufs_released.wrapping_add(1);
+            });
+            free_packet(pkt)
+        }
+        raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RELEASED => {
+            let cid = l2cap_evt.local_cid;
+            connection::try_with_state_by_conn_handle(l2cap_evt.conn_handle, |state| {
+                if let Some(i) = state.l2cap_channels.iter().position(|ch| ch.info.cid == cid) {
+                    // Frees the packets nobody picked up and wakes the tasks waiting on the channel.
+                    state.l2cap_channels.swap_remove(i);
+                }
+            });
+            if let Some(portal) = connection::try_portal(&PORTALS, l2cap_evt.conn_handle) {
+                portal.call(ble_evt);
+            }
+        }
+        raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RX => {
+            let params = &l2cap_evt.params.rx;
+            let cid = l2cap_evt.local_cid;
+            let sdu = ReceivedSdu {
+                ptr: unwrap!(NonNull::new(params.sdu_buf.p_data)),
+                len: params.sdu_len,
+            };
+            let queued = connection::try_with_state_by_conn_handle(l2cap_evt.conn_handle, |state| {
+                let ch = state.l2cap_channels.iter_mut().find(|ch| ch.info.cid == cid)?;
+                ch.rx_posted = ch.rx_posted.saturating_sub(1);
+                ch.stats.rx_sdus = ch.stats.rx_sdus.wrapping_add(1);
+                ch.stats.rx_bytes = ch.stats.rx_bytes.wrapping_add(sdu.len as u32);
+                // Buffers are only posted while there's room for them in the queue, so this
+                // can't fail.
+                let queued = ch.rx_queue.push(sdu).ok();
+                ch.wakers.wake();
+                queued
+            })
+            .flatten();
+            if queued.is_none() {
+                // Only possible if the channel is gone, its buffers are freed with it.
+                warn!("dropping packet received on unknown cid {:?}", cid);
+                free_packet(unwrap!(NonNull::new(params.sdu_buf.p_data)));
+            }
         }
         raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_TX => {
             let params = &l2cap_evt.params.tx;
             let pkt = unwrap!(NonNull::new(params.sdu_buf.p_data));
-            portal(l2cap_evt.conn_handle).call(ble_evt);
-            (unwrap!(PACKET_FREE))(pkt)
+            let cid = l2cap_evt.local_cid;
+            connection::try_with_state_by_conn_handle(l2cap_evt.conn_handle, |state| {
+                if let Some(ch) = state.l2cap_channels.iter_mut().find(|ch| ch.info.cid == cid) {
+                    ch.stats.tx_sdus = ch.stats.tx_sdus.wrapping_add(1);
+                    ch.stats.tx_bytes = ch.stats.tx_bytes.wrapping_add(params.sdu_buf.len as u32);
+                    // There's room in the transmit queue now.
+                    ch.wakers.wake();
+                }
+            });
+            free_packet(pkt)
         }
         _ => {
-            portal(l2cap_evt.conn_handle).call(ble_evt);
+            if let Some(portal) = connection::try_portal(&PORTALS, l2cap_evt.conn_handle) {
+                portal.call(ble_evt);
+            }
         }
     };
 }
 
+/// Update the statistics of a tracked channel, if it's still open.
+fn update_stats(conn_handle: u16, cid: u16, f: impl FnOnce(&mut ChannelStats)) {
+    connection::try_with_state_by_conn_handle(conn_handle, |state| {
+        if let Some(ch) = state.l2cap_channels.iter_mut().find(|ch| ch.info.cid == cid) {
+            f(&mut ch.stats);
+        }
+    });
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum TxError<P: Packet> {
     Disconnected,
     TxQueueFull(P),
+    /// The timeout passed to [`Channel::tx_with_timeout`] completed before the packet could
+    /// be queued. The packet is returned.
+    Timeout(P),
+    AllocateFailed,
     Raw(RawError),
 }
 
@@ -91,12 +202,42 @@ impl<P: Packet> From<RawError> for TxError<P> {
         TxError::Raw(err)
     }
 }
+
+impl<P: Packet> TxError<P> {
+    /// Recover the packet from a `TxQueueFull` or `Timeout` error.
+    pub fn into_packet(self) -> Option<P> {
+        match self {
+            TxError::TxQueueFull(pkt) | TxError::Timeout(pkt) => Some(pkt),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the error is `TxQueueFull`.
+    pub fn is_queue_full(&self) -> bool {
+        matches!(self, TxError::TxQueueFull(_))
+    }
+}
+
+impl<P: Packet> core::fmt::Display for TxError<P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TxError::Disconnected => f.write_str("connection disconnected"),
+            TxError::TxQueueFull(_) => f.write_str("L2CAP transmit queue full"),
+            TxError::Timeout(_) => f.write_str("L2CAP transmit timed out"),
+            TxError::AllocateFailed => f.write_str("failed to allocate a transmit buffer"),
+            TxError::Raw(err) => write!(f, "SoftDevice error: {:?}", err),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum RxError {
     Disconnected,
     AllocateFailed,
+    /// The timeout passed to [`Channel::rx_with_timeout`] completed first.
+    Timeout,
     Raw(RawError),
 }
 
@@ -112,12 +253,64 @@ impl From<RawError> for RxError {
     }
 }
 
+impl core::fmt::Display for RxError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RxError::Disconnected => f.write_str("connection disconnected"),
+            RxError::AllocateFailed => f.write_str("failed to allocate a receive buffer"),
+            RxError::Timeout => f.write_str("L2CAP receive timed out"),
+            RxError::Raw(err) => write!(f, "SoftDevice error: {:?}", err),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum DisconnectError {
+    Disconnected,
+    Raw(RawError),
+}
+
+impl From<DisconnectedError> for DisconnectError {
+    fn from(_err: DisconnectedError) -> Self {
+        DisconnectError::Disconnected
+    }
+}
+
+impl From<RawError> for DisconnectError {
+    fn from(err: RawError) -> Self {
+        DisconnectError::Raw(err)
+    }
+}
+
+impl core::fmt::Display for DisconnectError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DisconnectError::Disconnected => f.write_str("connection disconnected"),
+            DisconnectError::Raw(err) => write!(f, "SoftDevice error: {:?}", err),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum SetupError {
     Disconnected,
-    Refused,
+    /// The channel setup was refused, carrying the status code from the response.
+    Refused(RejectReason),
+    /// The channel requires an encrypted link, see [`Config::require_encryption`].
+    InsufficientEncryption,
+    /// The connection already has as many channels open as the driver tracks, 4.
+    TooManyChannels,
+    /// The `Config` is invalid for this packet type or SoftDevice configuration.
+    InvalidConfig(ConfigError),
+    /// The peer didn't answer the setup request before the SoftDevice's L2CAP signaling
+    /// timeout, or the timeout passed to [`L2cap::listen_with_timeout`] completed first.
+    Timeout,
+    /// Another task is already in [`L2cap::setup`] or [`L2cap::listen`] on this connection.
+    Busy,
     Raw(RawError),
 }
 
@@ -133,6 +326,77 @@ impl From<RawError> for SetupError {
     }
 }
 
+impl From<ConfigError> for SetupError {
+    fn from(err: ConfigError) -> Self {
+        SetupError::InvalidConfig(err)
+    }
+}
+
+/// Reason for refusing a channel setup request, sent or received as the
+/// LE credit based connection response result.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum RejectReason {
+    PsmNotSupported,
+    NoResources,
+    InsufficientAuthentication,
+    InsufficientAuthorization,
+    InsufficientEncryptionKeySize,
+    InsufficientEncryption,
+    UnacceptableParams,
+    /// Any other status code, such as one generated locally by the SoftDevice.
+    Other(u16),
+}
+
+impl RejectReason {
+    fn from_raw(status: u16) -> Self {
+        match status as u32 {
+            raw::BLE_L2CAP_CH_STATUS_CODE_LE_PSM_NOT_SUPPORTED => RejectReason::PsmNotSupported,
+            raw::BLE_L2CAP_CH_STATUS_CODE_NO_RESOURCES => RejectReason::NoResources,
+            raw::BLE_L2CAP_CH_STATUS_CODE_INSUFF_AUTHENTICATION => RejectReason::InsufficientAuthentication,
+            raw::BLE_L2CAP_CH_STATUS_CODE_INSUFF_AUTHORIZATION => RejectReason::InsufficientAuthorization,
+            raw::BLE_L2CAP_CH_STATUS_CODE_INSUFF_ENC_KEY_SIZE => RejectReason::InsufficientEncryptionKeySize,
+            raw::BLE_L2CAP_CH_STATUS_CODE_INSUFF_ENC => RejectReason::InsufficientEncryption,
+            raw::BLE_L2CAP_CH_STATUS_CODE_UNACCEPTABLE_PARAMS => RejectReason::UnacceptableParams,
+            _ => RejectReason::Other(status),
+        }
+    }
+
+    /// The LE credit based connection response result code for this reason.
+    pub fn status_code(self) -> u16 {
+        self.to_raw() as u16
+    }
+
+    fn to_raw(self) -> u32 {
+        match self {
+            RejectReason::PsmNotSupported => raw::BLE_L2CAP_CH_STATUS_CODE_LE_PSM_NOT_SUPPORTED,
+            RejectReason::NoResources => raw::BLE_L2CAP_CH_STATUS_CODE_NO_RESOURCES,
+            RejectReason::InsufficientAuthentication => raw::BLE_L2CAP_CH_STATUS_CODE_INSUFF_AUTHENTICATION,
+            RejectReason::InsufficientAuthorization => raw::BLE_L2CAP_CH_STATUS_CODE_INSUFF_AUTHORIZATION,
+            RejectReason::InsufficientEncryptionKeySize => raw::BLE_L2CAP_CH_STATUS_CODE_INSUFF_ENC_KEY_SIZE,
+            RejectReason::InsufficientEncryption => raw::BLE_L2CAP_CH_STATUS_CODE_INSUFF_ENC,
+            RejectReason::UnacceptableParams => raw::BLE_L2CAP_CH_STATUS_CODE_UNACCEPTABLE_PARAMS,
+            RejectReason::Other(status) => status as u32,
+        }
+    }
+}
+
+impl core::fmt::Display for SetupError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SetupError::Disconnected => f.write_str("connection disconnected"),
+            SetupError::Refused(reason) => write!(f, "L2CAP channel setup refused: {:?}", reason),
+            SetupError::InsufficientEncryption => f.write_str("L2CAP channel requires an encrypted link"),
+            SetupError::TooManyChannels => f.write_str("too many L2CAP channels on the connection"),
+            SetupError::InvalidConfig(err) => write!(f, "invalid L2CAP config: {}", err),
+            SetupError::Timeout => f.write_str("L2CAP channel setup timed out"),
+            SetupError::Busy => f.write_str("another L2CAP channel setup is in progress on this connection"),
+            SetupError::Raw(err) => write!(f, "SoftDevice error: {:?}", err),
+        }
+    }
+}
+
 const PORTAL_NEW: Portal<*const raw::ble_evt_t> = Portal::new();
 static PORTALS: [Portal<*const raw::ble_evt_t>; CONNS_MAX] = [PORTAL_NEW; CONNS_MAX];
 pub(crate) fn portal(conn_handle: u16) -> &'static Portal<*const raw::ble_evt_t> {
@@ -146,6 +410,10 @@ pub(crate) fn portal(conn_handle: u16) -> &'static Portal<*const raw::ble_evt_t>
 /// You need to implement this trait to give the L2CAP driver
 /// a method to allocate and free the space for the packets
 /// sent and received on a channel.
+///
+/// The driver has no separate free function: when the SoftDevice hands a buffer back, the
+/// driver rebuilds the `Packet` with `from_raw_parts` and drops it, so `Drop` must release
+/// the buffer, e.g. by returning it to a pool. [`FixedPacket`] does this.
 pub trait Packet: Sized {
     /// The maximum size a packet can have.
     const MTU: usize;
@@ -171,6 +439,222 @@ pub trait Packet: Sized {
     /// `len` must be the number of bytes in the buffer and must not be larger
     /// than `MTU`.
     unsafe fn from_raw_parts(ptr: NonNull<u8>, len: usize) -> Self;
+
+    /// The maximum size this packet can have, `MTU`.
+    fn capacity(&self) -> usize {
+        Self::MTU
+    }
+}
+
+/// A source of fixed-size packet buffers for [`FixedPacket`].
+///
+/// This is usually implemented by forwarding to a `static` [`PacketPoolStorage`].
+pub trait PacketPool {
+    /// Size of each buffer.
+    const MTU: usize;
+    /// Allocate a buffer of `MTU` bytes, or return `None` if none is available.
+    fn allocate() -> Option<NonNull<u8>>;
+    /// Return a buffer to the pool.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `allocate`, and must not be used afterwards.
+    unsafe fn free(ptr: NonNull<u8>);
+}
+
+/// A ready-made [`Packet`] holding up to `A::MTU` bytes in a buffer taken from the pool `A`.
+///
+/// The buffer is returned to the pool when the packet is dropped.
+pub struct FixedPacket<A: PacketPool> {
+    ptr: NonNull<u8>,
+    len: usize,
+    _pool: PhantomData<A>,
+}
+
+impl<A: PacketPool> FixedPacket<A> {
+    /// Allocate an empty packet, or return `None` if the pool is exhausted.
+    pub fn new() -> Option<Self> {
+        let ptr = A::allocate()?;
+        Some(Self {
+            ptr,
+            len: 0,
+            _pool: PhantomData,
+        })
+    }
+
+    /// Allocate a packet holding a copy of `data`, or return `None` if the pool is exhausted.
+    ///
+    /// Panics if `data` is longer than `A::MTU`.
+    pub fn from_slice(data: &[u8]) -> Option<Self> {
+        let mut pkt = Self::new()?;
+        pkt.set_len(data.len());
+        pkt.as_mut_slice().copy_from_slice(data);
+        Some(pkt)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Set the length of the packet. Bytes added this way keep whatever the buffer held.
+    ///
+    /// Panics if `len` is larger than `A::MTU`.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= A::MTU);
+        self.len = len;
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // Safety: the buffer holds `A::MTU` bytes, and `len <= A::MTU`.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safety: the buffer holds `A::MTU` bytes, and `len <= A::MTU`.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<A: PacketPool> Packet for FixedPacket<A> {
+    const MTU: usize = A::MTU;
+
+    fn allocate() -> Option<NonNull<u8>> {
+        A::allocate()
+    }
+
+    fn into_raw_parts(self) -> (NonNull<u8>, usize) {
+        let parts = (self.ptr, self.len);
+        mem::forget(self);
+        parts
+    }
+
+    unsafe fn from_raw_parts(ptr: NonNull<u8>, len: usize) -> Self {
+        Self {
+            ptr,
+            len,
+            _pool: PhantomData,
+        }
+    }
+}
+
+impl<A: PacketPool> Drop for FixedPacket<A> {
+    fn drop(&mut self) {
+        unsafe { A::free(self.ptr) }
+    }
+}
+
+/// Define a [`PacketPool`] named `$name`, backed by a static [`PacketPoolStorage`] of `$n`
+/// buffers of `$mtu` bytes.
+///
+/// ```ignore
+/// nrf_softdevice::packet_pool!(pub Pool, 128, 8);
+///
+/// type MyPacket = FixedPacket<Pool>;
+/// ```
+#[macro_export]
+macro_rules! packet_pool {
+    ($vis:vis $name:ident, $mtu:expr, $n:expr) => {
+        $vis struct $name;
+
+        impl $name {
+            fn storage() -> &'static $crate::ble::l2cap::PacketPoolStorage<{ $mtu }, { $n }> {
+                static STORAGE: $crate::ble::l2cap::PacketPoolStorage<{ $mtu }, { $n }> =
+                    $crate::ble::l2cap::PacketPoolStorage::new();
+                &STORAGE
+            }
+        }
+
+        impl $crate::ble::l2cap::PacketPool for $name {
+            const MTU: usize = $mtu;
+
+            fn allocate() -> ::core::option::Option<::core::ptr::NonNull<u8>> {
+                Self::storage().allocate()
+            }
+
+            unsafe fn free(ptr: ::core::ptr::NonNull<u8>) {
+                Self::storage().free(ptr)
+            }
+        }
+    };
+}
+
+/// Storage for up to 32 buffers of `MTU` bytes, to back a [`PacketPool`].
+///
+/// [`packet_pool!`](crate::packet_pool) defines a pool with its storage. Done by hand, it looks like this:
+///
+/// ```ignore
+/// static POOL: PacketPoolStorage<128, 8> = PacketPoolStorage::new();
+///
+/// struct Pool;
+/// impl PacketPool for Pool {
+///     const MTU: usize = 128;
+///     fn allocate() -> Option<NonNull<u8>> {
+///         POOL.allocate()
+///     }
+///     unsafe fn free(ptr: NonNull<u8>) {
+///         POOL.free(ptr)
+///     }
+/// }
+///
+/// type MyPacket = FixedPacket<Pool>;
+/// ```
+pub struct PacketPoolStorage<const MTU: usize, const N: usize> {
+    bufs: [UnsafeCell<[u8; MTU]>; N],
+    /// Bit `i` is set while `bufs[i]` is allocated.
+    used: AtomicU32,
+}
+
+// Safety: each buffer is only handed out once at a time, as tracked by `used`.
+unsafe impl<const MTU: usize, const N: usize> Sync for PacketPoolStorage<MTU, N> {}
+
+impl<const MTU: usize, const N: usize> PacketPoolStorage<MTU, N> {
+    const BUF: UnsafeCell<[u8; MTU]> = UnsafeCell::new([0; MTU]);
+
+    pub const fn new() -> Self {
+        assert!(MTU > 0, "PacketPoolStorage buffers can't be empty");
+        assert!(N <= 32, "PacketPoolStorage holds at most 32 buffers");
+        Self {
+            bufs: [Self::BUF; N],
+            used: AtomicU32::new(0),
+        }
+    }
+
+    /// Take a free buffer, or return `None` if all are allocated.
+    pub fn allocate(&self) -> Option<NonNull<u8>> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let i = (!used).trailing_zeros() as usize;
+            if i >= N {
+                return None;
+            }
+            match self
+                .used
+                .compare_exchange_weak(used, used | 1 << i, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return NonNull::new(self.bufs[i].get() as *mut u8),
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    /// Return a buffer to the storage.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `allocate` on this storage, and must not be
+    /// used afterwards.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        let i = match (ptr.as_ptr() as usize).checked_sub(self.bufs.as_ptr() as usize) {
+            Some(offset) if offset % MTU == 0 => offset / MTU,
+            _ => N,
+        };
+        assert!(i < N, "freeing a buffer from another pool");
+        self.used.fetch_and(!(1 << i), Ordering::Release);
+    }
 }
 
 /// The L2CAP driver.
@@ -181,6 +665,15 @@ pub struct L2cap<P: Packet> {
 
 static IS_INIT: AtomicBool = AtomicBool::new(false);
 static mut PACKET_FREE: Option<unsafe fn(NonNull<u8>)> = None;
+static mut BUF_RELEASED_HOOK: Option<fn(NonNull<u8>)> = None;
+
+/// Free a buffer the SoftDevice is done with, notifying the `on_buf_released` hook first.
+unsafe fn free_packet(ptr: NonNull<u8>) {
+    if let Some(hook) = BUF_RELEASED_HOOK {
+        hook(ptr);
+    }
+    (unwrap!(PACKET_FREE))(ptr)
+}
 
 impl<P: Packet> L2cap<P> {
     /// Initialize the driver.
@@ -203,6 +696,30 @@ impl<P: Packet> L2cap<P> {
         Self { _private: PhantomData }
     }
 
+    /// Register `hook` to be called with every buffer the driver frees after the SoftDevice
+    /// is done with it: sent packets, receive buffers released with their channel, and received
+    /// packets the driver had to drop before they were picked up.
+    ///
+    /// `hook` is only a notification, called from the SoftDevice event handler right before
+    /// the buffer is freed by dropping the `Packet` rebuilt from it with `from_raw_parts`. It
+    /// must not free the buffer itself.
+    pub fn on_buf_released(&self, hook: fn(NonNull<u8>)) {
+        unsafe { BUF_RELEASED_HOOK = Some(hook) }
+    }
+
+    /// Get the L2CAP channels currently open on `conn`.
+    ///
+    /// At most 4 channels per connection can be open, see [`SetupError::TooManyChannels`].
+    pub fn channels(&self, conn: &Connection) -> impl Iterator<Item = ChannelInfo> {
+        let mut channels = [None; L2CAP_CHANNELS_MAX];
+        conn.with_state(|state| {
+            for (slot, ch) in channels.iter_mut().zip(state.l2cap_channels.iter()) {
+                *slot = Some(ch.info);
+            }
+        });
+        channels.into_iter().flatten()
+    }
+
     /// Send a setup request to the peer to establish a channel with the PSM given
     /// in `psm`. The peer will accept the request and establish a channel if it
     /// deems the PSM acceptable.
@@ -210,13 +727,24 @@ impl<P: Packet> L2cap<P> {
         let sd = unsafe { Softdevice::steal() };
 
         let conn_handle = conn.with_state(|state| state.check_connected())?;
+        if config.require_encryption && !conn.is_encrypted() {
+            return Err(SetupError::InsufficientEncryption);
+        }
+        if conn.with_state(|state| state.l2cap_channels.is_full()) {
+            return Err(SetupError::TooManyChannels);
+        }
+        if portal(conn_handle).is_waiting() {
+            return Err(SetupError::Busy);
+        }
+        config.check_setup::<P>(sd)?;
+
         let mut cid: u16 = raw::BLE_L2CAP_CID_INVALID as _;
         let params = raw::ble_l2cap_ch_setup_params_t {
             le_psm: psm,
             status: 0, // only used when responding
             rx_params: raw::ble_l2cap_ch_rx_params_t {
-                rx_mps: sd.l2cap_rx_mps,
-                rx_mtu: P::MTU as u16,
+                rx_mps: config.rx_mps(sd),
+                rx_mtu: config.rx_mtu::<P>(),
                 sdu_buf: raw::ble_data_t {
                     len: 0,
                     p_data: ptr::null_mut(),
@@ -231,48 +759,80 @@ impl<P: Packet> L2cap<P> {
         debug!("cid {:?}", cid);
 
         portal(conn_handle)
-            .wait_once(|ble_evt| unsafe {
-                match (*ble_evt).header.evt_id as u32 {
-                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => return Err(SetupError::Disconnected),
+            .wait_many(|ble_evt| unsafe {
+                let evt_id = (*ble_evt).header.evt_id as u32;
+                if evt_id == raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED {
+                    return Some(Err(SetupError::Disconnected));
+                }
+                // Events of other channels on the same connection may come in while waiting.
+                let l2cap_evt = get_union_field(ble_evt, &(*ble_evt).evt.l2cap_evt);
+                if l2cap_evt.local_cid != cid {
+                    return None;
+                }
+                match evt_id {
                     raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RELEASED => {
                         // It is possible to get L2CAP_EVT_CH_RELEASED for the
                         // "half-setup" channel if the conn gets disconnected while
                         // setting it up.
-                        return Err(SetupError::Disconnected);
+                        Some(Err(SetupError::Disconnected))
                     }
                     raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_SETUP => {
-                        let l2cap_evt = get_union_field(ble_evt, &(*ble_evt).evt.l2cap_evt);
-                        let _evt = &l2cap_evt.params.ch_setup;
+                        let evt = &l2cap_evt.params.ch_setup;
 
                         // default is 1
                         let _ = config.credits;
-                        #[cfg(not(feature = "ble-l2cap-credit-wrokaround"))]
-                        if config.credits != 1 {
-                            let ret =
-                                raw::sd_ble_l2cap_ch_flow_control(conn_handle, cid, config.credits, ptr::null_mut());
-                            if let Err(err) = RawError::convert(ret) {
-                                warn!("sd_ble_l2cap_ch_flow_control err {:?}", err);
-                                return Err(err.into());
-                            }
+                        #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+                        if let Err(err) = config.apply_credits(conn_handle, cid) {
+                            return Some(Err(err.into()));
+                        }
+
+                        // Another setup may have taken the last slot since the check above.
+                        if !register_channel::<P>(conn, config, cid, psm, evt.tx_params.tx_mtu) {
+                            release(conn_handle, cid);
+                            return Some(Err(SetupError::TooManyChannels));
                         }
 
-                        Ok(Channel {
+                        Some(Ok(Channel {
                             conn: conn.clone(),
                             cid,
+                            credits: config.credits,
+                            auto_credits: config.auto_credits,
+                            peer_mtu: evt.tx_params.tx_mtu,
+                            peer_mps: evt.tx_params.peer_mps,
+                            #[cfg(feature = "ble-l2cap-credit-workaround")]
+                            credit_refill: config.credit_refill(config.rx_mtu::<P>(), config.rx_mps(sd)),
                             _private: PhantomData,
-                        })
+                        }))
                     }
                     raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_SETUP_REFUSED => {
-                        let l2cap_evt = get_union_field(ble_evt, &(*ble_evt).evt.l2cap_evt);
-                        let _evt = &l2cap_evt.params.ch_setup_refused;
-                        Err(SetupError::Refused)
+                        let evt = &l2cap_evt.params.ch_setup_refused;
+                        debug!("l2cap setup refused: source={:?} status={:?}", evt.source, evt.status);
+                        if evt.source as u32 == raw::BLE_L2CAP_CH_SETUP_REFUSED_SRC_LOCAL
+                            && evt.status as u32 == raw::BLE_L2CAP_CH_STATUS_CODE_TIMEOUT
+                        {
+                            return Some(Err(SetupError::Timeout));
+                        }
+                        Some(Err(SetupError::Refused(RejectReason::from_raw(evt.status))))
                     }
-                    e => panic!("unexpected event {}", e),
+                    _ => None,
                 }
             })
             .await
     }
 
+    /// Set up a channel on `conn` with the same PSM as a previously open channel.
+    ///
+    /// `info` is typically taken from [`channels`][Self::channels] while the old
+    /// connection was still up, since its channels are forgotten on disconnect.
+    pub async fn reestablish(
+        &self,
+        info: &ChannelInfo,
+        conn: &Connection,
+        config: &Config,
+    ) -> Result<Channel<P>, SetupError> {
+        self.setup(conn, config, info.psm).await
+    }
+
     /// Listen for setup requests of the peer.
     /// When a setup request with the PSM given in `psm` comes in the channel
     /// is established.
@@ -282,17 +842,73 @@ impl<P: Packet> L2cap<P> {
             .map(|(_, ch)| ch)
     }
 
+    /// Like [`listen`][Self::listen], giving up when `timeout` completes first.
+    ///
+    /// `timeout` is any future, typically a timer such as `embassy_time::Timer::after(...)`.
+    /// If it completes before a matching setup request comes in, [`SetupError::Timeout`] is
+    /// returned. A channel established at the same time is returned instead.
+    pub async fn listen_with_timeout(
+        &self,
+        conn: &Connection,
+        config: &Config,
+        psm: u16,
+        timeout: impl Future<Output = ()>,
+    ) -> Result<Channel<P>, SetupError> {
+        // `select` polls the listen future first, so a channel already set up is never dropped.
+        match select(self.listen(conn, config, psm), timeout).await {
+            Either::First(res) => res,
+            Either::Second(()) => Err(SetupError::Timeout),
+        }
+    }
+
     /// Listen for setup requests of the peer.
     /// When a setup request comes in the PSM sent by the peer is passed to the
     /// `accept_psm` function. If it returns `true` the channel is established.
+    ///
+    /// If [`Config::require_encryption`] is set, requests received while the link
+    /// is not encrypted are refused with "insufficient encryption", and listening
+    /// continues.
     pub async fn listen_with(
         &self,
         conn: &Connection,
         config: &Config,
         mut accept_psm: impl FnMut(u16) -> bool,
+    ) -> Result<(u16, Channel<P>), SetupError> {
+        self.listen_with_config(conn, move |psm| accept_psm(psm).then_some(config))
+            .await
+    }
+
+    /// Listen for setup requests of the peer, with a per-PSM configuration.
+    /// When a setup request comes in the PSM sent by the peer is passed to the
+    /// `accept_psm` function. If it returns a `Config` the channel is established
+    /// using it, if it returns `None` the request is refused.
+    pub async fn listen_with_config<'c>(
+        &self,
+        conn: &Connection,
+        mut accept_psm: impl FnMut(u16) -> Option<&'c Config>,
+    ) -> Result<(u16, Channel<P>), SetupError> {
+        self.listen_with_policy(conn, move |psm| accept_psm(psm).ok_or(RejectReason::PsmNotSupported))
+            .await
+    }
+
+    /// Listen for setup requests of the peer, refusing them with a specific reason.
+    /// When a setup request comes in the PSM sent by the peer is passed to the
+    /// `accept_psm` function. If it returns a `Config` the channel is established
+    /// using it, if it returns an error the request is refused with that reason
+    /// and listening continues.
+    ///
+    /// If the returned `Config` is invalid, see [`SetupError::InvalidConfig`], the request
+    /// is refused with "unacceptable parameters" and the error is returned.
+    pub async fn listen_with_policy<'c>(
+        &self,
+        conn: &Connection,
+        mut accept_psm: impl FnMut(u16) -> Result<&'c Config, RejectReason>,
     ) -> Result<(u16, Channel<P>), SetupError> {
         let sd = unsafe { Softdevice::steal() };
         let conn_handle = conn.with_state(|state| state.check_connected())?;
+        if portal(conn_handle).is_waiting() {
+            return Err(SetupError::Busy);
+        }
 
         portal(conn_handle)
             .wait_many(|ble_evt| unsafe {
@@ -303,13 +919,30 @@ impl<P: Packet> L2cap<P> {
                         let evt = &l2cap_evt.params.ch_setup_request;
 
                         let mut cid: u16 = l2cap_evt.local_cid;
-                        if accept_psm(evt.le_psm) {
+                        let config = match accept_psm(evt.le_psm) {
+                            Ok(config) => config,
+                            Err(reason) => {
+                                refuse(conn_handle, cid, evt.le_psm, reason);
+                                return None;
+                            }
+                        };
+
+                        if let Err(err) = config.check_setup::<P>(sd) {
+                            refuse(conn_handle, cid, evt.le_psm, RejectReason::UnacceptableParams);
+                            Some(Err(err.into()))
+                        } else if config.require_encryption && !conn.is_encrypted() {
+                            refuse(conn_handle, cid, evt.le_psm, RejectReason::InsufficientEncryption);
+                            None
+                        } else if conn.with_state(|state| state.l2cap_channels.is_full()) {
+                            refuse(conn_handle, cid, evt.le_psm, RejectReason::NoResources);
+                            Some(Err(SetupError::TooManyChannels))
+                        } else {
                             let params = raw::ble_l2cap_ch_setup_params_t {
                                 le_psm: evt.le_psm,
                                 status: raw::BLE_L2CAP_CH_STATUS_CODE_SUCCESS as _,
                                 rx_params: raw::ble_l2cap_ch_rx_params_t {
-                                    rx_mps: sd.l2cap_rx_mps,
-                                    rx_mtu: P::MTU as u16,
+                                    rx_mps: config.rx_mps(sd),
+                                    rx_mtu: config.rx_mtu::<P>(),
                                     sdu_buf: raw::ble_data_t {
                                         len: 0,
                                         p_data: ptr::null_mut(),
@@ -325,18 +958,14 @@ impl<P: Packet> L2cap<P> {
 
                             // default is 1
                             let _ = config.credits;
-                            #[cfg(not(feature = "ble-l2cap-credit-wrokaround"))]
-                            if config.credits != 1 {
-                                let ret = raw::sd_ble_l2cap_ch_flow_control(
-                                    conn_handle,
-                                    cid,
-                                    config.credits,
-                                    ptr::null_mut(),
-                                );
-                                if let Err(err) = RawError::convert(ret) {
-                                    warn!("sd_ble_l2cap_ch_flow_control err {:?}", err);
-                                    return Some(Err(err.into()));
-                                }
+                            #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+                            if let Err(err) = config.apply_credits(conn_handle, cid) {
+                                return Some(Err(err.into()));
+                            }
+
+                            if !register_channel::<P>(conn, config, cid, evt.le_psm, evt.tx_params.tx_mtu) {
+                                release(conn_handle, cid);
+                                return Some(Err(SetupError::TooManyChannels));
                             }
 
                             Some(Ok((
@@ -345,42 +974,378 @@ impl<P: Packet> L2cap<P> {
                                     _private: PhantomData,
                                     cid,
                                     conn: conn.clone(),
+                                    credits: config.credits,
+                                    auto_credits: config.auto_credits,
+                                    peer_mtu: evt.tx_params.tx_mtu,
+                                    peer_mps: evt.tx_params.peer_mps,
+                                    #[cfg(feature = "ble-l2cap-credit-workaround")]
+                                    credit_refill: config.credit_refill(config.rx_mtu::<P>(), config.rx_mps(sd)),
                                 },
                             )))
-                        } else {
-                            let params = raw::ble_l2cap_ch_setup_params_t {
-                                le_psm: evt.le_psm,
-                                status: raw::BLE_L2CAP_CH_STATUS_CODE_LE_PSM_NOT_SUPPORTED as _,
-                                rx_params: mem::zeroed(),
-                            };
-
-                            let ret = raw::sd_ble_l2cap_ch_setup(conn_handle, &mut cid, &params);
-                            if let Err(_err) = RawError::convert(ret) {
-                                warn!("sd_ble_l2cap_ch_setup err {:?}", _err);
-                            }
-
-                            None
                         }
                     }
-                    e => panic!("unexpected event {}", e),
+                    // Events of channels already established on the connection.
+                    _ => None,
                 }
             })
             .await
     }
 }
 
-/// Configuration for an L2CAP channel.
-pub struct Config {
-    /// Number of credits that the SoftDevice will make sure the peer
-    /// has every time it starts using a new reception buffer.
-    pub credits: u16,
+/// Track a newly established channel in the connection state. Returns false if there's no room left.
+fn register_channel<P: Packet>(conn: &Connection, config: &Config, cid: u16, psm: u16, tx_mtu: u16) -> bool {
+    let info = ChannelInfo {
+        cid,
+        psm,
+        rx_mtu: config.rx_mtu::<P>(),
+        tx_mtu,
+    };
+    let ch = ChannelState {
+        info,
+        #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+        credits: config.credits_setting(),
+        rx_posted: 0,
+        rx_queue: heapless::Vec::new(),
+        wakers: WakerSet::new(),
+        stats: ChannelStats::default(),
+    };
+    conn.with_state(|state| {
+        if state.l2cap_channels.push(ch).is_err() {
+            warn!("too many L2CAP channels, releasing cid {:?}", cid);
+            return false;
+        }
+        true
+    })
 }
 
-/// An L2CAP connection oriented channel.
-pub struct Channel<P: Packet> {
+/// Release a channel that was set up but can't be used, without waiting for it.
+unsafe fn release(conn_handle: u16, cid: u16) {
+    let ret = raw::sd_ble_l2cap_ch_release(conn_handle, cid);
+    if let Err(_err) = RawError::convert(ret) {
+        warn!("sd_ble_l2cap_ch_release err {:?}", _err);
+    }
+}
+
+/// Refuse a setup request from the peer.
+unsafe fn refuse(conn_handle: u16, mut cid: u16, psm: u16, reason: RejectReason) {
+    let params = raw::ble_l2cap_ch_setup_params_t {
+        le_psm: psm,
+        status: reason.to_raw() as _,
+        rx_params: mem::zeroed(),
+    };
+
+    let ret = raw::sd_ble_l2cap_ch_setup(conn_handle, &mut cid, &params);
+    if let Err(_err) = RawError::convert(ret) {
+        warn!("sd_ble_l2cap_ch_setup err {:?}", _err);
+    }
+}
+
+/// Configuration for an L2CAP channel.
+pub struct Config {
+    /// Number of credits that the SoftDevice will make sure the peer
+    /// has every time it starts using a new reception buffer.
+    pub credits: u16,
+    /// Grant the peer one credit every time a receive buffer is handed to the
+    /// SoftDevice, instead of letting the SoftDevice issue credits on its own.
+    ///
+    /// `credits` is ignored when this is set. Both are ignored when the
+    /// `ble-l2cap-credit-workaround` feature is enabled.
+    pub auto_credits: bool,
+    /// Only establish the channel if the link is encrypted.
+    ///
+    /// `setup` fails with [`SetupError::InsufficientEncryption`] without contacting the
+    /// peer, and `listen` refuses the peer's request with "insufficient encryption".
+    pub require_encryption: bool,
+    /// Number of SDUs worth of receive buffering the peer is allowed to fill ahead of us with
+    /// the `ble-l2cap-credit-workaround` feature. The peer's credits are topped up to cover
+    /// that many SDUs whenever they fall to half of it.
+    ///
+    /// 0 keeps the defaults of topping up to 65535 credits once below 1024. Ignored without
+    /// the feature.
+    pub rx_buffer_packets: u16,
+    /// Receive MTU to advertise to the peer, i.e. the largest SDU it may send us.
+    ///
+    /// `None` advertises `P::MTU`, the capacity of the packet type. A smaller value saves peer
+    /// resources while still using the same packet type. Must be at least 23, and not more
+    /// than `P::MTU`.
+    pub rx_mtu: Option<u16>,
+    /// Maximum PDU payload size to advertise to the peer, i.e. the largest PDU it may send us.
+    ///
+    /// `None` advertises [`Softdevice::l2cap_rx_mps`], the most the SoftDevice is configured
+    /// for. Must be at least 23, and not more than that.
+    pub rx_mps: Option<u16>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            credits: raw::BLE_L2CAP_CREDITS_DEFAULT as u16,
+            auto_credits: false,
+            require_encryption: false,
+            rx_buffer_packets: 0,
+            rx_mtu: None,
+            rx_mps: None,
+        }
+    }
+}
+
+impl Config {
+    /// The receive MPS advertised for channels.
+    fn rx_mps(&self, sd: &Softdevice) -> u16 {
+        self.rx_mps.unwrap_or(sd.l2cap_rx_mps)
+    }
+
+    /// Check the receive MTU and MPS against `P` and the limits of the specification.
+    fn check_sizes<P: Packet>(&self) -> Result<(), ConfigError> {
+        let rx_mtu = self.rx_mtu.map_or(P::MTU, |rx_mtu| rx_mtu as usize);
+        if rx_mtu > P::MTU || rx_mtu > u16::MAX as usize {
+            return Err(ConfigError::RxMtuTooLarge);
+        }
+        if rx_mtu < raw::BLE_L2CAP_MTU_MIN as usize {
+            return Err(ConfigError::RxMtuTooSmall);
+        }
+        match self.rx_mps {
+            Some(rx_mps) if rx_mps < raw::BLE_L2CAP_MPS_MIN as u16 => Err(ConfigError::RxMpsTooSmall),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the config before using it to set up a channel with packet type `P`.
+    fn check_setup<P: Packet>(&self, sd: &Softdevice) -> Result<(), ConfigError> {
+        self.check_sizes::<P>()?;
+        if self.rx_mps(sd) > sd.l2cap_rx_mps {
+            return Err(ConfigError::RxMpsTooLarge);
+        }
+        Ok(())
+    }
+
+    /// The receive MTU advertised for channels using packet type `P`, once checked.
+    fn rx_mtu<P: Packet>(&self) -> u16 {
+        self.rx_mtu.unwrap_or(P::MTU as u16)
+    }
+
+    /// Credit count below which the workaround refills the peer's credits, and the count it
+    /// refills them to, for SDUs of up to `rx_mtu` bytes sent in PDUs of up to `rx_mps` bytes.
+    #[cfg(feature = "ble-l2cap-credit-workaround")]
+    fn credit_refill(&self, rx_mtu: u16, rx_mps: u16) -> (u16, u16) {
+        if self.rx_buffer_packets == 0 {
+            return (1024, 0xFFFF);
+        }
+        // Each credit is one PDU, and the first PDU of an SDU also carries its 2 byte length.
+        let pdus_per_sdu = (rx_mtu as u32 + 2 + rx_mps as u32 - 1) / rx_mps as u32;
+        let max = (self.rx_buffer_packets as u32 * pdus_per_sdu).min(0xFFFF) as u16;
+        (max / 2, max)
+    }
+
+    /// The credits value passed to `sd_ble_l2cap_ch_flow_control` for this config.
+    #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+    fn credits_setting(&self) -> u16 {
+        if self.auto_credits {
+            0
+        } else {
+            self.credits
+        }
+    }
+
+    #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+    fn apply_credits(&self, conn_handle: u16, cid: u16) -> Result<(), RawError> {
+        let credits = self.credits_setting();
+        if credits != raw::BLE_L2CAP_CREDITS_DEFAULT as u16 {
+            let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn_handle, cid, credits, ptr::null_mut()) };
+            if let Err(err) = RawError::convert(ret) {
+                warn!("sd_ble_l2cap_ch_flow_control err {:?}", err);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start building a validated `Config`.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum ConfigError {
+    /// `credits` is 0 without `auto_credits`, so the peer could never send.
+    ZeroCredits,
+    /// `rx_mtu` is larger than `P::MTU`, or `P::MTU` doesn't fit in 16 bits without it.
+    RxMtuTooLarge,
+    /// The receive MTU is below the minimum of 23 bytes.
+    RxMtuTooSmall,
+    /// `rx_mps` is below the minimum of 23 bytes.
+    RxMpsTooSmall,
+    /// `rx_mps` is larger than [`Softdevice::l2cap_rx_mps`]. Only checked when setting up a
+    /// channel.
+    RxMpsTooLarge,
+}
+
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigError::ZeroCredits => f.write_str("L2CAP credits must be non-zero"),
+            ConfigError::RxMtuTooLarge => f.write_str("L2CAP rx_mtu is larger than Packet::MTU"),
+            ConfigError::RxMtuTooSmall => f.write_str("L2CAP rx_mtu is below the minimum of 23"),
+            ConfigError::RxMpsTooSmall => f.write_str("L2CAP rx_mps is below the minimum of 23"),
+            ConfigError::RxMpsTooLarge => f.write_str("L2CAP rx_mps is larger than the SoftDevice's configured MPS"),
+        }
+    }
+}
+
+/// Builder for a [`Config`], checking that the options are consistent.
+///
+/// Constructing a `Config` directly is still possible for simple cases.
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// See [`Config::credits`].
+    pub fn credits(mut self, credits: u16) -> Self {
+        self.config.credits = credits;
+        self
+    }
+
+    /// See [`Config::auto_credits`].
+    pub fn auto_credits(mut self, auto_credits: bool) -> Self {
+        self.config.auto_credits = auto_credits;
+        self
+    }
+
+    /// See [`Config::require_encryption`].
+    pub fn require_encryption(mut self, require_encryption: bool) -> Self {
+        self.config.require_encryption = require_encryption;
+        self
+    }
+
+    /// See [`Config::rx_buffer_packets`].
+    pub fn rx_buffer_packets(mut self, rx_buffer_packets: u16) -> Self {
+        self.config.rx_buffer_packets = rx_buffer_packets;
+        self
+    }
+
+    /// See [`Config::rx_mtu`].
+    pub fn rx_mtu(mut self, rx_mtu: u16) -> Self {
+        self.config.rx_mtu = Some(rx_mtu);
+        self
+    }
+
+    /// See [`Config::rx_mps`].
+    pub fn rx_mps(mut self, rx_mps: u16) -> Self {
+        self.config.rx_mps = Some(rx_mps);
+        self
+    }
+
+    /// Check the options and build the `Config`, for channels using packet type `P`.
+    ///
+    /// Whether `rx_mps` is within what the SoftDevice is configured for is only known when
+    /// setting up a channel.
+    pub fn build<P: Packet>(self) -> Result<Config, ConfigError> {
+        if self.config.credits == 0 && !self.config.auto_credits {
+            return Err(ConfigError::ZeroCredits);
+        }
+        self.config.check_sizes::<P>()?;
+        Ok(self.config)
+    }
+}
+
+/// Information about an open L2CAP channel, see [`L2cap::channels`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ChannelInfo {
+    /// Local channel identifier.
+    pub cid: u16,
+    pub psm: u16,
+    /// Maximum SDU size we can receive.
+    pub rx_mtu: u16,
+    /// Maximum SDU size the peer can receive.
+    pub tx_mtu: u16,
+}
+
+/// Counters for an L2CAP channel, see [`Channel::stats`].
+///
+/// Counters wrap around on overflow.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ChannelStats {
+    /// SDUs fully transmitted to the peer.
+    pub tx_sdus: u32,
+    /// Bytes in the SDUs fully transmitted to the peer.
+    pub tx_bytes: u32,
+    /// SDUs received from the peer.
+    pub rx_sdus: u32,
+    /// Bytes in the SDUs received from the peer.
+    pub rx_bytes: u32,
+    /// Attempts to queue an SDU that found the SoftDevice's transmit queue full.
+    pub tx_queue_full: u32,
+    /// Packet allocations that failed, when transmitting or receiving.
+    pub allocate_failed: u32,
+    /// Buffers handed back by the SoftDevice without being used, e.g. when the channel is released.
+    pub bufs_released: u32,
+}
+
+/// Tracking state of an open channel, kept in the connection state.
+pub(crate) struct ChannelState {
+    pub(crate) info: ChannelInfo,
+    /// Current credits setting of the channel's flow control.
+    #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+    pub(crate) credits: u16,
+    /// Receive buffers handed to the SoftDevice that haven't been filled yet.
+    pub(crate) rx_posted: u8,
+    /// Packets received on the channel, in reception order, until `Channel::rx` picks them up.
+    ///
+    /// This keeps a packet from being lost when no task is waiting for it, because the `rx`
+    /// future was dropped or because the buffer was posted by `try_rx`. Buffers are only
+    /// posted while `rx_posted + rx_queue.len()` is below `RX_QUEUE_LEN`, so every buffer the
+    /// SoftDevice fills has a slot here.
+    pub(crate) rx_queue: heapless::Vec<ReceivedSdu, RX_QUEUE_LEN>,
+    /// Tasks waiting to receive, to transmit or for the channel to be released. Woken on
+    /// every event of the channel, and when it goes away.
+    pub(crate) wakers: WakerSet<CHANNEL_WAKERS_MAX>,
+    pub(crate) stats: ChannelStats,
+}
+
+impl ChannelState {
+    /// Number of receive buffers that can be posted without overflowing `rx_queue`.
+    fn rx_room(&self) -> usize {
+        RX_QUEUE_LEN - self.rx_posted as usize - self.rx_queue.len()
+    }
+}
+
+impl Drop for ChannelState {
+    fn drop(&mut self) {
+        // Free the packets nobody picked up. Buffers still held by the SoftDevice are
+        // freed on SDU_BUF_RELEASED.
+        for sdu in self.rx_queue.iter() {
+            unsafe { free_packet(sdu.ptr) }
+        }
+        self.wakers.wake();
+    }
+}
+
+/// An L2CAP connection oriented channel.
+///
+/// Clones of a channel can be used from several tasks at once, e.g. one receiving and
+/// one transmitting. At most 4 tasks can wait on the same channel at the same time.
+/// With more, they keep waking each other up.
+pub struct Channel<P: Packet> {
     _private: PhantomData<*mut P>,
     conn: Connection,
     cid: u16,
+    credits: u16,
+    auto_credits: bool,
+    peer_mtu: u16,
+    peer_mps: u16,
+    /// Thresholds for `credit_hack_refill`.
+    #[cfg(feature = "ble-l2cap-credit-workaround")]
+    credit_refill: (u16, u16),
 }
 
 impl<P: Packet> Clone for Channel<P> {
@@ -389,6 +1354,12 @@ impl<P: Packet> Clone for Channel<P> {
             _private: PhantomData,
             conn: self.conn.clone(),
             cid: self.cid,
+            credits: self.credits,
+            auto_credits: self.auto_credits,
+            peer_mtu: self.peer_mtu,
+            peer_mps: self.peer_mps,
+            #[cfg(feature = "ble-l2cap-credit-workaround")]
+            credit_refill: self.credit_refill,
         }
     }
 }
@@ -399,6 +1370,90 @@ impl<P: Packet> Channel<P> {
         &self.conn
     }
 
+    /// Get the local channel identifier.
+    pub fn cid(&self) -> u16 {
+        self.cid
+    }
+
+    /// Returns true if the connection is up and the channel hasn't been released.
+    pub fn is_connected(&self) -> bool {
+        self.conn.with_state(|state| {
+            state.conn_handle.is_some() && state.l2cap_channels.iter().any(|ch| ch.info.cid == self.cid)
+        })
+    }
+
+    /// Get the handle of the underlying connection, or `None` if it's disconnected.
+    pub fn connection_handle(&self) -> Option<u16> {
+        self.conn.handle()
+    }
+
+    /// Get the channel's counters.
+    ///
+    /// Once the channel is released, this returns all zeros.
+    pub fn stats(&self) -> ChannelStats {
+        self.conn.with_state(|state| {
+            state
+                .l2cap_channels
+                .iter()
+                .find(|ch| ch.info.cid == self.cid)
+                .map(|ch| ch.stats)
+                .unwrap_or_default()
+        })
+    }
+
+    /// Reset the channel's counters to zero.
+    pub fn reset_stats(&self) {
+        self.update_stats(|stats| *stats = ChannelStats::default());
+    }
+
+    fn update_stats(&self, f: impl FnOnce(&mut ChannelStats)) {
+        self.conn.with_state(|state| {
+            if let Some(ch) = state.l2cap_channels.iter_mut().find(|ch| ch.info.cid == self.cid) {
+                f(&mut ch.stats);
+            }
+        });
+    }
+
+    /// Register the current task to be woken on the next event of this channel.
+    ///
+    /// Returns false if the channel or the connection went away instead.
+    fn register_waker(&self, cx: &Context<'_>) -> bool {
+        self.conn.with_state(|state| {
+            let ch = state.l2cap_channels.iter_mut().find(|ch| ch.info.cid == self.cid);
+            match ch {
+                Some(ch) => {
+                    ch.wakers.register(cx.waker());
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Allocate a packet buffer, counting failures.
+    fn allocate(&self) -> Option<NonNull<u8>> {
+        let ptr = P::allocate();
+        if ptr.is_none() {
+            self.update_stats(|stats| stats.allocate_failed = stats.allocate_failed.wrapping_add(1));
+        }
+        ptr
+    }
+
+    /// Maximum SDU size the peer can receive, in bytes.
+    ///
+    /// Packets passed to [`tx`][Self::tx] must not be larger than this.
+    pub fn peer_mtu(&self) -> u16 {
+        self.peer_mtu
+    }
+
+    /// Maximum PDU payload size the peer can receive, in bytes.
+    ///
+    /// SDUs larger than this are segmented into several PDUs. The PDU payload size we can
+    /// receive is [`Softdevice::l2cap_rx_mps`], the same for all channels.
+    pub fn peer_mps(&self) -> u16 {
+        self.peer_mps
+    }
+
     /// Try to queue a packet for transmission.
     ///
     /// This takes ownership of the packet but you will get it back in the
@@ -415,7 +1470,15 @@ impl<P: Packet> Channel<P> {
 
         let ret = unsafe { raw::sd_ble_l2cap_ch_tx(conn_handle, self.cid, &data) };
         match RawError::convert(ret) {
-            Err(RawError::Resources) => Err(TxError::TxQueueFull(unsafe { P::from_raw_parts(ptr, len) })),
+            Err(RawError::Resources) => {
+                self.update_stats(|stats| stats.tx_queue_full = stats.tx_queue_full.wrapping_add(1));
+                Err(TxError::TxQueueFull(unsafe { P::from_raw_parts(ptr, len) }))
+            }
+            Err(RawError::NotFound) => {
+                // The channel has been released.
+                unsafe { P::from_raw_parts(ptr, len) };
+                Err(TxError::Disconnected)
+            }
             Err(err) => {
                 warn!("sd_ble_l2cap_ch_tx err {:?}", err);
                 // The SD didn't take ownership of the buffer, so it's on us to free it.
@@ -430,38 +1493,383 @@ impl<P: Packet> Channel<P> {
 
     /// Asynchronously transmit a packet.
     pub async fn tx(&self, mut sdu: P) -> Result<(), TxError<P>> {
-        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+        self.conn.with_state(|s| s.check_connected())?;
 
         loop {
             match self.try_tx(sdu) {
-                Ok(()) => {
-                    return Ok(());
-                }
                 Err(TxError::TxQueueFull(ret_sdu)) => {
                     sdu = ret_sdu;
-                    portal(conn_handle)
-                        .wait_once(|ble_evt| unsafe {
-                            match (*ble_evt).header.evt_id as u32 {
-                                raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_TX => (),
-                                raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RELEASED => (),
-                                _ => unreachable!("Invalid event"),
-                            }
-                        })
-                        .await;
-                    continue;
+                    if !self.wait_tx_done().await {
+                        return Err(TxError::Disconnected);
+                    }
                 }
-                Err(e) => {
-                    return Err(e);
+                res => return res,
+            }
+        }
+    }
+
+    /// Asynchronously transmit a packet made of `header` followed by `body`.
+    ///
+    /// The packet is allocated with `P::allocate`. Panics if `header` and `body` together
+    /// are longer than `P::MTU`.
+    pub async fn tx_vectored(&self, header: &[u8], body: &[u8]) -> Result<(), TxError<P>> {
+        let len = header.len() + body.len();
+        assert!(len <= P::MTU);
+
+        let ptr = self.allocate().ok_or(TxError::AllocateFailed)?;
+        // Safety: `ptr` has room for `P::MTU` bytes, and we own it until `from_raw_parts`.
+        let sdu = unsafe {
+            ptr::copy_nonoverlapping(header.as_ptr(), ptr.as_ptr(), header.len());
+            ptr::copy_nonoverlapping(body.as_ptr(), ptr.as_ptr().add(header.len()), body.len());
+            P::from_raw_parts(ptr, len)
+        };
+        self.tx(sdu).await
+    }
+
+    /// Asynchronously transmit a packet, giving up when `timeout` completes first.
+    ///
+    /// `timeout` is any future, typically a timer such as `embassy_time::Timer::after(...)`.
+    /// If it completes while waiting for room in the SoftDevice's transmit queue, the packet
+    /// is returned in [`TxError::Timeout`].
+    pub async fn tx_with_timeout(&self, mut sdu: P, timeout: impl Future<Output = ()>) -> Result<(), TxError<P>> {
+        self.conn.with_state(|s| s.check_connected())?;
+
+        pin_mut!(timeout);
+        loop {
+            match self.try_tx(sdu) {
+                Err(TxError::TxQueueFull(ret_sdu)) => {
+                    sdu = ret_sdu;
+                    match select(self.wait_tx_done(), &mut timeout).await {
+                        Either::First(true) => {}
+                        Either::First(false) => return Err(TxError::Disconnected),
+                        Either::Second(()) => return Err(TxError::Timeout(sdu)),
+                    }
                 }
+                res => return res,
+            }
+        }
+    }
+
+    /// Wait for an event of this channel, typically a packet being sent, which makes room in
+    /// its transmit queue.
+    ///
+    /// Returns false if the channel or the connection went away instead. Callers retry the
+    /// transmission on true, since the event may have been for something else.
+    async fn wait_tx_done(&self) -> bool {
+        let mut registered = false;
+        poll_fn(|cx| {
+            if registered {
+                return Poll::Ready(self.is_connected());
+            }
+            if !self.register_waker(cx) {
+                return Poll::Ready(false);
+            }
+            registered = true;
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Stop issuing credits to the peer, pausing its transmissions once it has
+    /// used up the credits it already has.
+    ///
+    /// Credits already granted can't be revoked, so the peer may still send that
+    /// many SDUs after this returns. With [`Config::auto_credits`] set, credits are
+    /// only granted by [`rx`][Self::rx], so this has no effect beyond not calling it.
+    #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+    pub fn pause_rx(&self) -> Result<(), RxError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+        self.set_flow_control(conn_handle, 0)
+    }
+
+    /// Resume issuing credits to the peer after [`pause_rx`][Self::pause_rx],
+    /// restoring [`Config::credits`].
+    ///
+    /// With [`Config::auto_credits`] set, this does nothing.
+    #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+    pub fn resume_rx(&self) -> Result<(), RxError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+        if self.auto_credits {
+            return Ok(());
+        }
+
+        self.set_flow_control(conn_handle, self.credits)
+    }
+
+    /// Set the number of credits the SoftDevice makes sure the peer has every time
+    /// it starts using a new receive buffer, replacing [`Config::credits`].
+    ///
+    /// With [`Config::auto_credits`] set, the next [`rx`][Self::rx] resets this to 0.
+    #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+    pub fn set_credits(&self, credits: u16) -> Result<(), RxError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+        self.set_flow_control(conn_handle, credits)
+    }
+
+    /// Get the number of credits the peer currently has, i.e. the number of
+    /// PDUs it can send before it needs more credits.
+    ///
+    /// The SoftDevice only reports the credits when the credits setting is applied, so this
+    /// applies the current setting again. The setting doesn't change, but if the peer has
+    /// fewer credits than it, the SoftDevice may top them up as it would on its own when
+    /// starting a new receive buffer.
+    #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+    pub fn peer_credits(&self) -> Result<u16, RxError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+
+        let setting = self.credits_setting();
+        let mut credits = 0;
+        let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn_handle, self.cid, setting, &mut credits) };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("sd_ble_l2cap_ch_flow_control credits query err {:?}", err);
+            return Err(err.into());
+        }
+        Ok(credits)
+    }
+
+    /// Current credits setting of the channel's flow control.
+    #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+    fn credits_setting(&self) -> u16 {
+        let tracked = self.conn.with_state(|state| {
+            state
+                .l2cap_channels
+                .iter()
+                .find(|ch| ch.info.cid == self.cid)
+                .map(|ch| ch.credits)
+        });
+        tracked.unwrap_or(if self.auto_credits { 0 } else { self.credits })
+    }
+
+    #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+    fn set_flow_control(&self, conn_handle: u16, credits: u16) -> Result<(), RxError> {
+        let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn_handle, self.cid, credits, ptr::null_mut()) };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("sd_ble_l2cap_ch_flow_control credits={:?} err {:?}", credits, err);
+            return Err(err.into());
+        }
+
+        self.conn.with_state(|state| {
+            if let Some(ch) = state.l2cap_channels.iter_mut().find(|ch| ch.info.cid == self.cid) {
+                ch.credits = credits;
+            }
+        });
+        Ok(())
+    }
+
+    /// Release the channel, keeping the connection up.
+    ///
+    /// Waits until the channel is released. Afterwards, `tx` and `rx` on this channel
+    /// fail with `Disconnected`. A channel that's already released counts as success.
+    pub async fn disconnect(&self) -> Result<(), DisconnectError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+
+        let ret = unsafe { raw::sd_ble_l2cap_ch_release(conn_handle, self.cid) };
+        match RawError::convert(ret) {
+            Ok(()) => {}
+            // Release already in progress.
+            Err(RawError::InvalidState) => {}
+            // Already released.
+            Err(RawError::NotFound) => return Ok(()),
+            Err(err) => {
+                warn!("sd_ble_l2cap_ch_release err {:?}", err);
+                return Err(err.into());
             }
         }
+
+        poll_fn(|cx| {
+            if self.register_waker(cx) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await;
+        Ok(())
     }
 
     /// Asynchronously receive a packet.
+    ///
+    /// This is cancel-safe: if the future is dropped after the receive buffer was handed
+    /// to the SoftDevice, the packet received into it is returned by the next receive call.
     pub async fn rx(&self) -> Result<P, RxError> {
         let conn_handle = self.conn.with_state(|s| s.check_connected())?;
 
-        let ptr = P::allocate().ok_or(RxError::AllocateFailed)?;
+        if let Some(pkt) = self.take_received() {
+            return Ok(pkt);
+        }
+        if self.rx_posted() == 0 {
+            let ptr = self.allocate().ok_or(RxError::AllocateFailed)?;
+            self.post_rx_buf(conn_handle, ptr)?;
+        }
+        self.wait_received().await
+    }
+
+    /// Asynchronously receive a packet, giving up when `timeout` completes first.
+    ///
+    /// `timeout` is any future, typically a timer such as `embassy_time::Timer::after(...)`.
+    /// If it completes first, [`RxError::Timeout`] is returned. The receive buffer stays
+    /// handed to the SoftDevice, and the packet received into it is returned by the next
+    /// receive call, like when an [`rx`][Self::rx] future is dropped.
+    pub async fn rx_with_timeout(&self, timeout: impl Future<Output = ()>) -> Result<P, RxError> {
+        match select(self.rx(), timeout).await {
+            Either::First(res) => res,
+            Either::Second(()) => Err(RxError::Timeout),
+        }
+    }
+
+    /// Asynchronously receive a packet, giving up after `n` connection intervals.
+    ///
+    /// The timeout scales with the connection interval in use when this is called, see
+    /// [`Connection::conn_params`]. `delay` turns the resulting duration, in microseconds, into
+    /// a timer future, for example `|us| Timer::after(Duration::from_micros(us))`. The
+    /// rest behaves like [`rx_with_timeout`][Self::rx_with_timeout].
+    pub async fn rx_timeout_intervals<F: Future<Output = ()>>(
+        &self,
+        n: u16,
+        delay: impl FnOnce(u64) -> F,
+    ) -> Result<P, RxError> {
+        // Units of 1.25ms.
+        let interval = self.conn.conn_params().max_conn_interval as u64;
+        self.rx_with_timeout(delay(interval * 1250 * n as u64)).await
+    }
+
+    /// Receive a packet if one is available, without waiting.
+    ///
+    /// If no packet has been received yet, a receive buffer is handed to the SoftDevice,
+    /// unless one already is, and `Ok(None)` is returned. The buffer stays registered with
+    /// the channel: the packet received into it is returned by the next `try_rx`, `rx` or
+    /// `rx_multiple` call, and it's freed when the channel is released.
+    pub fn try_rx(&self) -> Result<Option<P>, RxError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+
+        if let Some(pkt) = self.take_received() {
+            return Ok(Some(pkt));
+        }
+        if self.rx_posted() == 0 {
+            let ptr = self.allocate().ok_or(RxError::AllocateFailed)?;
+            self.post_rx_buf(conn_handle, ptr)?;
+        }
+        Ok(None)
+    }
+
+    /// Asynchronously receive a packet into `sdu`, instead of allocating a new one.
+    ///
+    /// Any data already in `sdu` is discarded. If a packet was already received into another
+    /// buffer, that one is returned and `sdu` is freed. The same happens if the channel already
+    /// has as many receive buffers handed to the SoftDevice as it can queue packets for: `sdu`
+    /// is freed and the next packet received into one of those is returned. On error, the buffer
+    /// is freed: immediately if the SoftDevice didn't take it, or when the SoftDevice releases it
+    /// otherwise.
+    pub async fn rx_into(&self, sdu: P) -> Result<P, RxError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+
+        if let Some(pkt) = self.take_received() {
+            return Ok(pkt);
+        }
+        // With nothing posted, posting reports a released channel.
+        if self.rx_posted() == 0 || self.rx_room() > 0 {
+            let (ptr, _) = sdu.into_raw_parts();
+            self.post_rx_buf(conn_handle, ptr)?;
+        } else {
+            drop(sdu);
+        }
+        self.wait_received().await
+    }
+
+    /// Wait for a packet to be received into one of the buffers handed to the SoftDevice.
+    async fn wait_received(&self) -> Result<P, RxError> {
+        poll_fn(|cx| {
+            if let Some(pkt) = self.take_received() {
+                return Poll::Ready(Ok(pkt));
+            }
+            if !self.register_waker(cx) {
+                return Poll::Ready(Err(RxError::Disconnected));
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Receive packets into `bufs` until it's full.
+    ///
+    /// Packets already received are added first. Then a receive buffer is allocated for every
+    /// free slot in `bufs` not covered by a buffer already handed to the SoftDevice, and all of
+    /// them are handed over at once, so the peer can keep sending while earlier packets are
+    /// processed. This waits until every buffer handed to the SoftDevice has been filled, or
+    /// `bufs` is full, and returns the number of packets added to `bufs`. If fewer buffers can
+    /// be allocated than there are free slots, only those are used.
+    ///
+    /// A channel has at most 8 receive buffers handed to the SoftDevice at once, so with `N`
+    /// above 8 a call returns after at most 8 packets.
+    pub async fn rx_multiple<const N: usize>(&self, bufs: &mut heapless::Vec<P, N>) -> Result<usize, RxError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+
+        let mut received = 0;
+        while !bufs.is_full() {
+            match self.take_received() {
+                Some(pkt) => {
+                    // Can't fail, `bufs` isn't full.
+                    let _ = bufs.push(pkt);
+                    received += 1;
+                }
+                None => break,
+            }
+        }
+
+        let mut pending = self.rx_posted() as usize;
+        let mut room = self.rx_room();
+        while bufs.len() + pending < N && room > 0 {
+            let ptr = match self.allocate() {
+                Some(ptr) => ptr,
+                None => break,
+            };
+            if let Err(err) = self.post_rx_buf(conn_handle, ptr) {
+                if pending == 0 && received == 0 {
+                    return Err(err);
+                }
+                break;
+            }
+            pending += 1;
+            room -= 1;
+        }
+
+        let wanted = bufs.len() + pending.min(N - bufs.len());
+        if bufs.len() == wanted {
+            return if received > 0 || bufs.is_full() {
+                Ok(received)
+            } else if !self.is_connected() {
+                Err(RxError::Disconnected)
+            } else {
+                Err(RxError::AllocateFailed)
+            };
+        }
+
+        poll_fn(|cx| {
+            while bufs.len() < wanted {
+                match self.take_received() {
+                    Some(pkt) => {
+                        // Can't fail, `wanted` is at most `N`.
+                        let _ = bufs.push(pkt);
+                        received += 1;
+                    }
+                    None => break,
+                }
+            }
+            if bufs.len() == wanted {
+                return Poll::Ready(Ok(received));
+            }
+            if !self.register_waker(cx) {
+                return Poll::Ready(Err(RxError::Disconnected));
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Hand `ptr`, a buffer of `P::MTU` bytes, to the SoftDevice to receive a packet into.
+    ///
+    /// Callers check `rx_room` first. On error, the buffer is freed.
+    fn post_rx_buf(&self, conn_handle: u16, ptr: NonNull<u8>) -> Result<(), RxError> {
         let data = raw::ble_data_t {
             p_data: ptr.as_ptr(),
             len: P::MTU as u16,
@@ -469,33 +1877,99 @@ impl<P: Packet> Channel<P> {
 
         let ret = unsafe { raw::sd_ble_l2cap_ch_rx(conn_handle, self.cid, &data) };
         if let Err(err) = RawError::convert(ret) {
-            warn!("sd_ble_l2cap_ch_rx err {:?}", err);
             // The SD didn't take ownership of the buffer, so it's on us to free it.
             // Reconstruct the P and let it get dropped.
             unsafe { P::from_raw_parts(ptr, 0) };
+            if err == RawError::NotFound {
+                // The channel has been released.
+                return Err(RxError::Disconnected);
+            }
+            warn!("sd_ble_l2cap_ch_rx err {:?}", err);
             return Err(err.into());
         }
 
-        #[cfg(feature = "ble-l2cap-credit-wrokaround")]
-        credit_hack_refill(conn_handle, self.cid);
+        self.conn.with_state(|state| {
+            if let Some(ch) = state.l2cap_channels.iter_mut().find(|ch| ch.info.cid == self.cid) {
+                ch.rx_posted += 1;
+            }
+        });
 
-        portal(conn_handle)
-            .wait_many(|ble_evt| unsafe {
-                match (*ble_evt).header.evt_id as u32 {
-                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => Some(Err(RxError::Disconnected)),
-                    raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RELEASED => Some(Err(RxError::Disconnected)),
-                    raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RX => {
-                        let l2cap_evt = get_union_field(ble_evt, &(*ble_evt).evt.l2cap_evt);
-                        let evt = &l2cap_evt.params.rx;
+        #[cfg(feature = "ble-l2cap-credit-workaround")]
+        credit_hack_refill(conn_handle, self.cid, self.credit_refill);
+        #[cfg(not(feature = "ble-l2cap-credit-workaround"))]
+        if self.auto_credits {
+            grant_credit(conn_handle, self.cid);
+        }
 
-                        let ptr = unwrap!(NonNull::new(evt.sdu_buf.p_data));
-                        let len = evt.sdu_len;
-                        let pkt = Packet::from_raw_parts(ptr, len as usize);
-                        Some(Ok(pkt))
-                    }
-                    _ => None,
-                }
-            })
-            .await
+        Ok(())
+    }
+
+    /// Number of receive buffers of this channel held by the SoftDevice.
+    fn rx_posted(&self) -> u8 {
+        self.conn.with_state(|state| {
+            state
+                .l2cap_channels
+                .iter()
+                .find(|ch| ch.info.cid == self.cid)
+                .map_or(0, |ch| ch.rx_posted)
+        })
+    }
+
+    /// Number of receive buffers that can still be handed to the SoftDevice, see
+    /// `ChannelState::rx_queue`.
+    fn rx_room(&self) -> usize {
+        self.conn.with_state(|state| {
+            state
+                .l2cap_channels
+                .iter()
+                .find(|ch| ch.info.cid == self.cid)
+                .map_or(0, |ch| ch.rx_room())
+        })
+    }
+
+    /// Take the oldest packet received on this channel out of its receive queue.
+    fn take_received(&self) -> Option<P> {
+        let sdu = self.conn.with_state(|state| {
+            let ch = state.l2cap_channels.iter_mut().find(|ch| ch.info.cid == self.cid)?;
+            if ch.rx_queue.is_empty() {
+                return None;
+            }
+            // Keep the remaining packets in reception order.
+            ch.rx_queue.rotate_left(1);
+            ch.rx_queue.pop()
+        })?;
+
+        assert!(
+            sdu.len as usize <= P::MTU,
+            "received SDU of {} bytes, larger than MTU {}",
+            sdu.len,
+            P::MTU
+        );
+        Some(unsafe { P::from_raw_parts(sdu.ptr, sdu.len as usize) })
+    }
+
+    /// Asynchronously receive a packet and pass its contents to `f`.
+    ///
+    /// The packet is freed as soon as `f` returns.
+    pub async fn rx_with<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Result<R, RxError> {
+        let (ptr, len) = self.rx().await?.into_raw_parts();
+        // Safety: `into_raw_parts` returns a buffer holding `len` bytes, which we own until
+        // handing it back to `from_raw_parts` below.
+        let res = f(unsafe { slice::from_raw_parts(ptr.as_ptr(), len) });
+        drop(unsafe { P::from_raw_parts(ptr, len) });
+        Ok(res)
+    }
+
+    /// Receive packets and forward them to `sender` until an error occurs, typically
+    /// a disconnection, which is returned.
+    ///
+    /// No new packet is received while `sender`'s channel is full.
+    pub async fn pipe_rx_to<M: RawMutex, const N: usize>(&self, sender: Sender<'_, M, P, N>) -> RxError {
+        loop {
+            match self.rx().await {
+                Ok(pkt) => sender.send(pkt).await,
+                Err(err) => return err,
+            }
+        }
     }
 }
@@ -14,6 +14,8 @@ pub use types::*;
 
 mod common;
 
+pub mod advertisement;
+
 #[cfg(feature = "ble-sec")]
 pub mod security;
 
@@ -375,7 +375,7 @@ where
                     match f(conn_handle, role, peer_address, conn_params) {
                         Ok(conn) => {
                             #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
-                            gap::do_data_length_update(conn_handle, ptr::null());
+                            let _ = gap::do_data_length_update(conn_handle, ptr::null());
 
                             Ok(conn)
                         }
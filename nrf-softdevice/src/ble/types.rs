@@ -177,6 +177,22 @@ impl TryFrom<u8> for AddressType {
     }
 }
 
+/// The address bytes don't match the bit pattern required by the address type.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct InvalidAddress;
+
+/// The string is not an address of the form `AA:BB:CC:DD:EE:FF`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ParseAddressError;
+
+/// A Bluetooth device address, along with its [`AddressType`].
+///
+/// This is passed to the SoftDevice as is: the type is the `addr_type` of `ble_gap_addr_t`, and
+/// [`is_resolved_peer_id`](Self::is_resolved_peer_id) its `addr_id_peer` flag.
 // Note: this type MUST be layout-compatible with raw::ble_gap_addr_t
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -206,6 +222,73 @@ impl Address {
         }
     }
 
+    /// Create a public address. `bytes` are in little-endian order.
+    pub const fn public(bytes: [u8; 6]) -> Self {
+        Self::new(AddressType::Public, bytes)
+    }
+
+    /// Create a random static address. `bytes` are in little-endian order.
+    ///
+    /// The two most significant bits must be `0b11`, and the remaining bits must be neither all 0 nor all 1.
+    pub fn random_static(bytes: [u8; 6]) -> Result<Self, InvalidAddress> {
+        Self::new_random(AddressType::RandomStatic, 0b11, &bytes, bytes)
+    }
+
+    /// Create a resolvable private address. `bytes` are in little-endian order.
+    ///
+    /// The two most significant bits must be `0b01`, and the remaining bits of the random part (the 3 most
+    /// significant bytes) must be neither all 0 nor all 1.
+    pub fn random_private_resolvable(bytes: [u8; 6]) -> Result<Self, InvalidAddress> {
+        Self::new_random(AddressType::RandomPrivateResolvable, 0b01, &bytes[3..], bytes)
+    }
+
+    /// Create a non-resolvable private address. `bytes` are in little-endian order.
+    ///
+    /// The two most significant bits must be `0b00`, and the remaining bits must be neither all 0 nor all 1.
+    pub fn random_private_non_resolvable(bytes: [u8; 6]) -> Result<Self, InvalidAddress> {
+        Self::new_random(AddressType::RandomPrivateNonResolvable, 0b00, &bytes, bytes)
+    }
+
+    fn new_random(
+        address_type: AddressType,
+        type_bits: u8,
+        random: &[u8],
+        bytes: [u8; 6],
+    ) -> Result<Self, InvalidAddress> {
+        let (msb, rest) = unwrap!(random.split_last());
+        if msb >> 6 != type_bits {
+            return Err(InvalidAddress);
+        }
+        let msb = msb & 0x3f;
+        let all_zeros = msb == 0 && rest.iter().all(|&b| b == 0);
+        let all_ones = msb == 0x3f && rest.iter().all(|&b| b == 0xff);
+        if all_zeros || all_ones {
+            return Err(InvalidAddress);
+        }
+        Ok(Self::new(address_type, bytes))
+    }
+
+    /// Parse an address written most significant byte first, as in `AA:BB:CC:DD:EE:FF`.
+    ///
+    /// The address type can't be told from the string, so it has to be passed in. Like
+    /// [`Address::new`], this doesn't check the address against the type.
+    pub fn parse(address_type: AddressType, s: &str) -> Result<Self, ParseAddressError> {
+        let mut bytes = [0; 6];
+        let mut parts = s.split(':');
+        for byte in bytes.iter_mut().rev() {
+            let part = parts.next().ok_or(ParseAddressError)?;
+            // Checked first, `from_str_radix` would accept a sign.
+            if part.len() != 2 || !part.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(ParseAddressError);
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| ParseAddressError)?;
+        }
+        if parts.next().is_some() {
+            return Err(ParseAddressError);
+        }
+        Ok(Self::new(address_type, bytes))
+    }
+
     pub fn address_type(&self) -> AddressType {
         unwrap!((self.flags >> 1).try_into())
     }
@@ -229,6 +312,14 @@ impl Address {
     }
 }
 
+/// Formats the address most significant byte first, as in `AA:BB:CC:DD:EE:FF`.
+impl core::fmt::Display for Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [b0, b1, b2, b3, b4, b5] = self.bytes;
+        write!(f, "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", b5, b4, b3, b2, b1, b0)
+    }
+}
+
 #[cfg(feature = "defmt")]
 impl defmt::Format for Address {
     fn format(&self, fmt: defmt::Formatter) {
@@ -240,6 +331,28 @@ impl defmt::Format for Address {
     }
 }
 
+/// HCI status code, as reported by the SoftDevice, e.g. as the reason for a disconnection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct HciStatus(pub u8);
+
+impl HciStatus {
+    pub const SUCCESS: Self = Self(raw::BLE_HCI_STATUS_CODE_SUCCESS as u8);
+    pub const AUTHENTICATION_FAILURE: Self = Self(raw::BLE_HCI_AUTHENTICATION_FAILURE as u8);
+    pub const CONNECTION_TIMEOUT: Self = Self(raw::BLE_HCI_CONNECTION_TIMEOUT as u8);
+    pub const REMOTE_USER_TERMINATED_CONNECTION: Self = Self(raw::BLE_HCI_REMOTE_USER_TERMINATED_CONNECTION as u8);
+    pub const REMOTE_DEV_TERMINATION_DUE_TO_LOW_RESOURCES: Self =
+        Self(raw::BLE_HCI_REMOTE_DEV_TERMINATION_DUE_TO_LOW_RESOURCES as u8);
+    pub const REMOTE_DEV_TERMINATION_DUE_TO_POWER_OFF: Self =
+        Self(raw::BLE_HCI_REMOTE_DEV_TERMINATION_DUE_TO_POWER_OFF as u8);
+    pub const LOCAL_HOST_TERMINATED_CONNECTION: Self = Self(raw::BLE_HCI_LOCAL_HOST_TERMINATED_CONNECTION as u8);
+    pub const LMP_RESPONSE_TIMEOUT: Self = Self(raw::BLE_HCI_STATUS_CODE_LMP_RESPONSE_TIMEOUT as u8);
+    pub const CONN_INTERVAL_UNACCEPTABLE: Self = Self(raw::BLE_HCI_CONN_INTERVAL_UNACCEPTABLE as u8);
+    pub const CONN_TERMINATED_DUE_TO_MIC_FAILURE: Self = Self(raw::BLE_HCI_CONN_TERMINATED_DUE_TO_MIC_FAILURE as u8);
+    pub const CONN_FAILED_TO_BE_ESTABLISHED: Self = Self(raw::BLE_HCI_CONN_FAILED_TO_BE_ESTABLISHED as u8);
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[derive(Eq, PartialEq, Copy, Clone)]
@@ -280,6 +393,18 @@ pub enum Phy {
     Coded = 4,
 }
 
+impl Phy {
+    pub fn try_from_raw(raw: u8) -> Option<Self> {
+        match raw as u32 {
+            raw::BLE_GAP_PHY_1MBPS => Some(Phy::M1),
+            raw::BLE_GAP_PHY_2MBPS => Some(Phy::M2),
+            #[cfg(feature = "s140")]
+            raw::BLE_GAP_PHY_CODED => Some(Phy::Coded),
+            _ => None,
+        }
+    }
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[derive(Eq, PartialEq, Copy, Clone)]
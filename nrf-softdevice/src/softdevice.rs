@@ -36,7 +36,6 @@ pub struct Softdevice {
     // Prevent Send, Sync
     _private: PhantomData<*mut ()>,
     #[cfg(feature = "ble-gatt")]
-    #[allow(unused)]
     pub(crate) att_mtu: u16,
     #[cfg(feature = "ble-l2cap")]
     pub(crate) l2cap_rx_mps: u16,
@@ -302,6 +301,27 @@ impl Softdevice {
         &*SOFTDEVICE.as_ptr()
     }
 
+    /// Get the default ATT MTU, in bytes.
+    ///
+    /// This is the `att_mtu` from [`Config::conn_gatt`] passed to [`Softdevice::enable`], or
+    /// `BLE_GATT_ATT_MTU_DEFAULT` (23) if unset. It's the largest ATT MTU the SoftDevice supports
+    /// on a connection, and the MTU requested by [`ble::central::connect`] unless overridden in
+    /// its config. It can't be changed once the softdevice is enabled.
+    #[cfg(feature = "ble-gatt")]
+    pub fn default_att_mtu(&self) -> u16 {
+        self.att_mtu
+    }
+
+    /// Get the L2CAP receive MPS, in bytes.
+    ///
+    /// This is the `rx_mps` from [`Config::conn_l2cap`] passed to [`Softdevice::enable`], or
+    /// `BLE_L2CAP_MPS_MIN` (23) if unset. It's the largest PDU payload the peer may send on the
+    /// L2CAP channels set up with [`ble::l2cap`], and can't be changed once the softdevice is enabled.
+    #[cfg(feature = "ble-l2cap")]
+    pub fn l2cap_rx_mps(&self) -> u16 {
+        self.l2cap_rx_mps
+    }
+
     /// Runs the softdevice event handling loop.
     ///
     /// It must be called in its own async task after enabling the softdevice
@@ -6,6 +6,8 @@ mod drop_bomb;
 pub use drop_bomb::*;
 mod on_drop;
 pub use on_drop::*;
+mod waker_set;
+pub use waker_set::*;
 
 use crate::raw;
 
@@ -52,6 +52,12 @@ impl<T> Portal<T> {
         })
     }
 
+    /// Returns true if a task is waiting on the portal, in [Portal::wait_once()] or
+    /// [Portal::wait_many()].
+    pub fn is_waiting(&self) -> bool {
+        self.state.lock(|state| state.borrow().0.is_some())
+    }
+
     /// Wait until the portal is called once using the [Portal::call()] function.
     ///
     /// The closure will be called with the parameter provided to [Portal::call()].
@@ -0,0 +1,39 @@
+use core::task::Waker;
+
+/// Wakers of up to `N` tasks waiting for the same thing.
+///
+/// A task that stops waiting, e.g. because its future is dropped in a `select`, keeps its
+/// slot until all slots are needed. Then all tasks are woken, and those still waiting
+/// register again. With more than `N` tasks waiting this happens on every registration,
+/// so they keep waking each other.
+pub struct WakerSet<const N: usize> {
+    wakers: heapless::Vec<Waker, N>,
+}
+
+impl<const N: usize> WakerSet<N> {
+    pub const fn new() -> Self {
+        Self {
+            wakers: heapless::Vec::new(),
+        }
+    }
+
+    /// Register `waker` to be woken by the next [`wake`][Self::wake].
+    pub fn register(&mut self, waker: &Waker) {
+        if self.wakers.iter().any(|w| w.will_wake(waker)) {
+            return;
+        }
+        if self.wakers.is_full() {
+            // Make room by waking everyone, those still waiting register again.
+            self.wake();
+        }
+        // Can't fail, there's room after the above.
+        let _ = self.wakers.push(waker.clone());
+    }
+
+    /// Wake all registered tasks.
+    pub fn wake(&mut self) {
+        while let Some(waker) = self.wakers.pop() {
+            waker.wake();
+        }
+    }
+}